@@ -1,8 +1,11 @@
 use super::*;
+pub use crate::util::config::SyncMode;
 pub use anyhow::Result;
 pub use dashmap::DashSet;
-pub use serde::Deserialize;
+pub use serde::{Deserialize, Serialize};
 pub use std::path::{Path, PathBuf};
+pub use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[derive(Deserialize, Clone)]
 #[serde(tag = "provider", rename_all = "snake_case")]
@@ -10,33 +13,247 @@ pub enum BackendOptions {
     S3(s3::S3Options),
 }
 
+impl BackendOptions {
+    pub fn provider(&self) -> &'static str {
+        match self {
+            BackendOptions::S3(_) => "s3",
+        }
+    }
+}
+
 pub static TRASH_PATH: &str = ".trash/";
+pub static TOMBSTONE_PATH: &str = ".tombstones/";
+pub static LEASE_PATH: &str = ".lease";
+pub static DEVICES_PATH: &str = ".devices";
+
+/// Keys used by rsink itself that must never be synced
+pub fn is_internal(key: &str) -> bool {
+    [TRASH_PATH, TOMBSTONE_PATH, LEASE_PATH, DEVICES_PATH]
+        .iter()
+        .any(|x| key.starts_with(x))
+}
+
+/// An advisory lock on the bucket, only the holder runs sync passes until it expires
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Lease {
+    pub device: String,
+    pub expires_at: i64,
+}
+
+/// An object stored in the cloud
+#[derive(Clone, Debug)]
+pub struct RemoteObject {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: String,
+}
+
+/// An entry of the device registry kept in the bucket
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    pub last_seen: i64,
+}
+
+#[derive(Debug)]
+pub struct HttpError(pub u16);
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unexpected HTTP status code {}", self.0)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/// Turns a non-2xx status code into an error
+pub fn check_status(code: u16) -> Result<()> {
+    if (200..300).contains(&code) {
+        Ok(())
+    } else {
+        Err(HttpError(code).into())
+    }
+}
 
 pub enum Operation {
-    Write(PathBuf),
+    /// Download the cloud object of the given size
+    Write(PathBuf, u64),
     WriteEmpty(PathBuf),
     Upload(PathBuf),
+    Remove(PathBuf),
     Checked(PathBuf),
+    /// The path was deliberately deleted from the cloud
+    Deleted(PathBuf),
+    /// Both copies changed since the last pass, The newer one wins
+    Conflict(PathBuf),
+    /// The path was deleted from the cloud, Remove the local copy
+    RemoveLocal(PathBuf),
 }
 
 impl Operation {
     pub fn path(&self) -> PathBuf {
         match self {
-            Operation::Write(p)
+            Operation::Write(p, _)
             | Operation::Upload(p)
+            | Operation::Remove(p)
             | Operation::Checked(p)
+            | Operation::Deleted(p)
+            | Operation::Conflict(p)
+            | Operation::RemoveLocal(p)
             | Operation::WriteEmpty(p) => p.clone(),
         }
     }
+
+    /// Adjust the operation to respect the given sync mode, `None` means skip it
+    pub fn with_mode(self, mode: SyncMode) -> Option<Self> {
+        match self {
+            Operation::Write(p, _) | Operation::WriteEmpty(p) if !mode.can_download() => {
+                if p.exists() {
+                    Some(Operation::Upload(p))
+                } else if mode == SyncMode::MirrorLocal {
+                    Some(Operation::Remove(p))
+                } else {
+                    None
+                }
+            }
+            Operation::Upload(p) if !mode.can_upload() => match mode {
+                SyncMode::MirrorRemote => {
+                    // Only the local size is known here, Both copies are versions of the same file
+                    let size = p.metadata().map(|m| m.len()).unwrap_or(0);
+                    Some(Operation::Write(p, size))
+                }
+                _ => None,
+            },
+            op => Some(op),
+        }
+    }
 }
 
 #[async_trait]
 pub trait Backend {
     async fn init(options: BackendOptions) -> Self;
+    async fn list(&self, prefix: &str) -> Result<Vec<RemoteObject>>;
+    /// Moves a removed file back from the trash
+    async fn restore(&self, path: &str) -> Result<()>;
     async fn remove(&self, path: &str) -> Result<()>;
     async fn download(&self, path: &str) -> Result<Vec<u8>>;
     async fn exists(&self, path: &str) -> Result<bool>;
     async fn rename(&self, old_path: &str, path: &str) -> Result<()>;
-    async fn sync(&self) -> Result<Vec<Operation>>;
+    /// A dry run must not modify the cloud
+    async fn sync(&self, dry_run: bool) -> Result<Vec<Operation>>;
     async fn upload(&self, path: &str, content: &[u8]) -> Result<()>;
+    /// Takes or renews the lease, returns the current holder if it belongs to another device
+    async fn acquire_lease(&self, device: &str, ttl: Duration) -> Result<Option<Lease>>;
+    async fn release_lease(&self, device: &str) -> Result<()>;
+    async fn devices(&self) -> Result<Vec<Device>>;
+    async fn register_device(&self, device: Device) -> Result<()>;
+    /// The ID of the device that last wrote the object, if known
+    ///
+    /// Renames and restores copy the object with its metadata, so this is the device
+    /// that wrote the content rather than the one that moved it
+    async fn last_writer(&self, path: &str) -> Result<Option<String>>;
+
+    /// Uploads the content as it is read, So the reader can pace the transfer
+    async fn upload_stream(
+        &self,
+        path: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<()> {
+        let mut content = vec![];
+        reader.read_to_end(&mut content).await?;
+        self.upload(path, &content).await
+    }
+
+    /// Downloads the object into `writer` as it is received, So the writer can pace the transfer
+    async fn download_stream(
+        &self,
+        path: &str,
+        writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<()> {
+        writer.write_all(&self.download(path).await?).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    /// A fresh scratch directory, `with_mode` only looks at the files in it
+    fn dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir()
+            .join(format!("rsink-test-{}", process::id()))
+            .join(name);
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn download_only_modes_never_upload() {
+        let path = dir("with_mode_download").join("file");
+
+        for mode in [SyncMode::DownloadOnly, SyncMode::MirrorRemote] {
+            assert!(matches!(
+                Operation::Write(path.clone(), 1).with_mode(mode),
+                Some(Operation::Write(..))
+            ));
+        }
+
+        assert!(Operation::Upload(path.clone())
+            .with_mode(SyncMode::DownloadOnly)
+            .is_none());
+        assert!(matches!(
+            Operation::Upload(path).with_mode(SyncMode::MirrorRemote),
+            Some(Operation::Write(..))
+        ));
+    }
+
+    #[test]
+    fn upload_only_modes_never_download() {
+        let dir = dir("with_mode_upload");
+        let existing = dir.join("existing");
+        let missing = dir.join("missing");
+
+        fs::write(&existing, b"local").unwrap();
+
+        for mode in [SyncMode::UploadOnly, SyncMode::MirrorLocal] {
+            assert!(matches!(
+                Operation::Write(existing.clone(), 1).with_mode(mode),
+                Some(Operation::Upload(_))
+            ));
+            assert!(matches!(
+                Operation::WriteEmpty(existing.clone()).with_mode(mode),
+                Some(Operation::Upload(_))
+            ));
+        }
+
+        assert!(Operation::Write(missing.clone(), 1)
+            .with_mode(SyncMode::UploadOnly)
+            .is_none());
+        assert!(matches!(
+            Operation::Write(missing, 1).with_mode(SyncMode::MirrorLocal),
+            Some(Operation::Remove(_))
+        ));
+    }
+
+    #[test]
+    fn bidirectional_keeps_every_operation() {
+        let path = dir("with_mode_bidirectional").join("file");
+
+        assert!(matches!(
+            Operation::Write(path.clone(), 1).with_mode(SyncMode::Bidirectional),
+            Some(Operation::Write(..))
+        ));
+        assert!(matches!(
+            Operation::Upload(path.clone()).with_mode(SyncMode::Bidirectional),
+            Some(Operation::Upload(_))
+        ));
+        assert!(matches!(
+            Operation::Checked(path).with_mode(SyncMode::Bidirectional),
+            Some(Operation::Checked(_))
+        ));
+    }
 }
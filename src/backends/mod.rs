@@ -1,11 +1,17 @@
 pub mod interface;
+pub mod retry;
 #[path = "./s3/s3.rs"]
 pub mod s3;
+pub mod throttle;
+pub mod track;
 pub use interface::*;
+pub use retry::*;
+pub use throttle::*;
+pub use track::*;
 
 pub async fn init_backend(options: BackendOptions) -> impl Backend {
     match options {
-        BackendOptions::S3(_) => s3::S3::init(options).await,
+        BackendOptions::S3(_) => Tracked::<Retrying<Throttled<s3::S3>>>::init(options).await,
         // _ => unreachable!()
     }
 }
@@ -0,0 +1,198 @@
+use super::interface::*;
+use crate::util::{config::CONFIG, notification::is_quota_error};
+use futures::Future;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+use tokio::time::sleep;
+
+/// The longest delay between two attempts
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Wraps a backend and retries its transient failures with exponential backoff
+pub struct Retrying<B> {
+    pub(super) inner: B,
+}
+
+/// Server errors, throttling and network failures, A full bucket stays full though
+fn is_transient(err: &anyhow::Error) -> bool {
+    if is_quota_error(err) {
+        return false;
+    }
+
+    if let Some(HttpError(code)) = err.downcast_ref::<HttpError>() {
+        return *code >= 500 || *code == 429;
+    }
+
+    if err
+        .chain()
+        .any(|x| x.downcast_ref::<std::io::Error>().is_some())
+    {
+        return true;
+    }
+
+    let message = format!("{err:#}").to_lowercase();
+
+    [
+        "timed out",
+        "timeout",
+        "connection",
+        "reset",
+        "throttl",
+        "slow down",
+        "temporarily",
+    ]
+    .iter()
+    .any(|x| message.contains(x))
+}
+
+fn backoff(attempt: u32) -> Duration {
+    let delay = Duration::from_millis(CONFIG.retry_delay)
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_DELAY);
+    let jitter = RandomState::new().build_hasher().finish() % (delay.as_millis() as u64 / 2 + 1);
+
+    delay / 2 + Duration::from_millis(jitter)
+}
+
+pub async fn retry<T, F, Fut>(mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+
+    loop {
+        match f().await {
+            Err(err) if attempt < CONFIG.retries && is_transient(&err) => {
+                let delay = backoff(attempt);
+                log::warn!("Transient error, Retrying in {delay:?}: {err:#}");
+                sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[async_trait]
+impl<B: Backend + Send + Sync> Backend for Retrying<B> {
+    async fn init(options: BackendOptions) -> Self {
+        Self {
+            inner: B::init(options).await,
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<RemoteObject>> {
+        retry(|| self.inner.list(prefix)).await
+    }
+
+    async fn restore(&self, path: &str) -> Result<()> {
+        retry(|| self.inner.restore(path)).await
+    }
+
+    async fn remove(&self, path: &str) -> Result<()> {
+        retry(|| self.inner.remove(path)).await
+    }
+
+    async fn download(&self, path: &str) -> Result<Vec<u8>> {
+        retry(|| self.inner.download(path)).await
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool> {
+        retry(|| self.inner.exists(path)).await
+    }
+
+    async fn rename(&self, old_path: &str, path: &str) -> Result<()> {
+        retry(|| self.inner.rename(old_path, path)).await
+    }
+
+    async fn sync(&self, dry_run: bool) -> Result<Vec<Operation>> {
+        retry(|| self.inner.sync(dry_run)).await
+    }
+
+    async fn upload(&self, path: &str, content: &[u8]) -> Result<()> {
+        retry(|| self.inner.upload(path, content)).await
+    }
+
+    async fn acquire_lease(&self, device: &str, ttl: Duration) -> Result<Option<Lease>> {
+        retry(|| self.inner.acquire_lease(device, ttl)).await
+    }
+
+    async fn release_lease(&self, device: &str) -> Result<()> {
+        retry(|| self.inner.release_lease(device)).await
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>> {
+        retry(|| self.inner.devices()).await
+    }
+
+    async fn register_device(&self, device: Device) -> Result<()> {
+        retry(|| self.inner.register_device(device.clone())).await
+    }
+
+    async fn last_writer(&self, path: &str) -> Result<Option<String>> {
+        retry(|| self.inner.last_writer(path)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing;
+    use anyhow::{anyhow, Context};
+    use std::io;
+
+    #[test]
+    fn server_and_network_errors_are_transient() {
+        assert!(is_transient(&HttpError(500).into()));
+        assert!(is_transient(&HttpError(503).into()));
+        assert!(is_transient(&HttpError(429).into()));
+        assert!(is_transient(
+            &Err::<(), _>(HttpError(502))
+                .context("Couldn't upload")
+                .unwrap_err()
+        ));
+        assert!(is_transient(
+            &io::Error::new(io::ErrorKind::ConnectionReset, "reset").into()
+        ));
+        assert!(is_transient(&anyhow!("operation timed out")));
+    }
+
+    #[test]
+    fn client_and_quota_errors_are_not_transient() {
+        assert!(!is_transient(&HttpError(403).into()));
+        assert!(!is_transient(&HttpError(404).into()));
+        assert!(!is_transient(&HttpError(507).into()));
+        assert!(!is_transient(
+            &Err::<(), _>(HttpError(503))
+                .context("QuotaExceeded: The bucket is full")
+                .unwrap_err()
+        ));
+        assert!(!is_transient(&anyhow!("invalid key")));
+    }
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        testing::init();
+
+        // retry_delay is 10 ms in the tests, with up to half of it as jitter
+        for (attempt, delay) in [(0, 10), (1, 20), (3, 80)] {
+            let backoff = backoff(attempt);
+            assert!(
+                backoff >= Duration::from_millis(delay / 2),
+                "{attempt}: {backoff:?}"
+            );
+            assert!(
+                backoff <= Duration::from_millis(delay),
+                "{attempt}: {backoff:?}"
+            );
+        }
+
+        for attempt in [20, 31, u32::MAX] {
+            assert!(backoff(attempt) >= MAX_DELAY / 2);
+            assert!(backoff(attempt) <= MAX_DELAY);
+        }
+    }
+}
@@ -1,7 +1,46 @@
 use super::interface::*;
-use crate::util::*;
-use s3::{creds::Credentials, Bucket, Region};
+use crate::util::{device::DEVICE_ID, *};
+use crate::SYNCED_STATES;
+use anyhow::Context;
+use s3::{creds::Credentials, request::ResponseData, Bucket, Region};
+use serde::de::DeserializeOwned;
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::sleep,
+};
+
+const LEASE_SETTLE_DELAY: Duration = Duration::from_secs(2);
+const DEVICE_METADATA: &str = "device";
+
+/// The text of the first `<tag>` of an S3 error body
+fn xml_field<'a>(body: &'a str, tag: &str) -> Option<&'a str> {
+    let start = body.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = body[start..].find(&format!("</{tag}>"))?;
+    Some(&body[start..start + end])
+}
+
+/// Like `check_status`, Adding the S3 error code and message of the body, e.g. `QuotaExceeded`
+fn check_response(res: &ResponseData) -> Result<()> {
+    check_status(res.status_code()).with_context(|| {
+        let body = String::from_utf8_lossy(res.bytes());
+
+        match (xml_field(&body, "Code"), xml_field(&body, "Message")) {
+            (Some(code), Some(message)) => format!("{code}: {message}"),
+            (Some(code), None) => code.to_owned(),
+            _ => "The request failed".to_owned(),
+        }
+    })
+}
+
+/// Whether a tombstone written at `last_modified` is older than `retention` days, 0 never expires
+///
+/// The listed time is the deletion time, so the tombstones don't have to be read
+fn is_expired(last_modified: &str, now: OffsetDateTime, retention: u64) -> bool {
+    retention > 0
+        && OffsetDateTime::parse(last_modified, &Rfc3339)
+            .is_ok_and(|at| now - at > time::Duration::days(retention as i64))
+}
 
 #[derive(Deserialize, Clone)]
 pub struct S3Options {
@@ -23,11 +62,47 @@ pub struct S3 {
     bucket: Bucket,
 }
 
+impl S3 {
+    async fn read_json<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        let res = self.bucket.get_object(key).await?;
+
+        if res.status_code() == 404 {
+            return Ok(None);
+        }
+
+        check_response(&res)?;
+
+        Ok(serde_json::from_slice(res.bytes()).ok())
+    }
+
+    async fn write_json<T: Serialize + Sync>(&self, key: &str, value: &T) -> Result<()> {
+        let res = self
+            .bucket
+            .put_object(key, &serde_json::to_vec(value)?)
+            .await?;
+        check_response(&res)
+    }
+
+    /// Marks `key` as deliberately deleted, The tombstone holds the deletion time
+    async fn write_tombstone(&self, key: &str) -> Result<()> {
+        let deleted_at = OffsetDateTime::now_utc().format(&Rfc3339)?;
+        let res = self
+            .bucket
+            .put_object(TOMBSTONE_PATH.to_owned() + key, deleted_at.as_bytes())
+            .await?;
+        check_response(&res)
+    }
+
+    async fn read_lease(&self) -> Result<Option<Lease>> {
+        self.read_json(LEASE_PATH).await
+    }
+}
+
 #[async_trait]
 impl Backend for S3 {
     async fn init(options: BackendOptions) -> Self {
         let BackendOptions::S3(opts) = options;
-        let bucket = Bucket::new(
+        let mut bucket = Bucket::new(
             &opts.bucket_name,
             Region::Custom {
                 endpoint: opts.endpoint.clone().unwrap_or_default(),
@@ -45,62 +120,145 @@ impl Backend for S3 {
         .unwrap()
         .with_path_style();
 
+        // Every written object records which device wrote it
+        bucket.add_header(&format!("x-amz-meta-{DEVICE_METADATA}"), &DEVICE_ID);
+
         Self { opts, bucket }
     }
 
-    async fn sync(&self) -> Result<Vec<Operation>> {
+    async fn list(&self, prefix: &str) -> Result<Vec<RemoteObject>> {
+        let prefix = if prefix.is_empty() { "/" } else { prefix };
+
+        Ok(self
+            .bucket
+            .list(prefix.to_owned(), None)
+            .await?
+            .into_iter()
+            .flat_map(|list| list.contents)
+            .map(|obj| RemoteObject {
+                key: obj.key,
+                size: obj.size,
+                last_modified: obj.last_modified,
+            })
+            .collect())
+    }
+
+    async fn restore(&self, path: &str) -> Result<()> {
+        check_status(
+            self.bucket
+                .copy_object_internal(TRASH_PATH.to_owned() + path, path)
+                .await?,
+        )?;
+        check_response(
+            &self
+                .bucket
+                .delete_object(TRASH_PATH.to_owned() + path)
+                .await?,
+        )?;
+        Ok(())
+    }
+
+    async fn sync(&self, dry_run: bool) -> Result<Vec<Operation>> {
         let mut operations = vec![];
+        let objects = self.list("").await?;
+        let now = OffsetDateTime::now_utc();
+        let tombstones = DashSet::new();
 
-        for list in self.bucket.list("/".to_owned(), None).await? {
-            for obj in list.contents {
-                if obj.key.starts_with(TRASH_PATH) {
-                    continue;
-                }
+        for obj in &objects {
+            let key = match obj.key.strip_prefix(TOMBSTONE_PATH) {
+                Some(key) => key,
+                None => continue,
+            };
+
+            if !is_expired(&obj.last_modified, now, config::CONFIG.tombstone_retention) {
+                tombstones.insert(key.to_owned());
+            } else if !dry_run {
+                log::debug!("The deletion of {key} is past its retention, Removing its tombstone");
+                check_response(&self.bucket.delete_object(&obj.key).await?)?;
+            }
+        }
+
+        for obj in objects {
+            if is_internal(&obj.key) || !is_selected(&obj.key) {
+                continue;
+            }
 
-                let path = key_to_path(&obj.key);
-                let (exists, size, last_modified) = metadata_of(&path).await;
+            if tombstones.remove(&obj.key).is_some() && !dry_run {
+                log::debug!("{} was re-created, Removing its tombstone", obj.key);
+                let res = self
+                    .bucket
+                    .delete_object(TOMBSTONE_PATH.to_owned() + &obj.key)
+                    .await?;
+                check_response(&res)?;
+            }
+
+            let path = key_to_path(&obj.key);
+            let (exists, size, last_modified) = metadata_of(&path).await;
 
-                if size == obj.size {
-                    if obj.size == 0 && !exists {
-                        operations.push(Operation::WriteEmpty(path));
-                    } else {
-                        operations.push(Operation::Checked(path))
+            if size == obj.size {
+                if !dry_run {
+                    if let Ok(cloud_modified) = OffsetDateTime::parse(&obj.last_modified, &Rfc3339)
+                    {
+                        SYNCED_STATES.set_cloud_modified(&obj.key, cloud_modified.unix_timestamp());
                     }
-                    continue;
                 }
 
-                log::debug!(
-                    "{:?} has different size, cloud({}) != local({})",
-                    path,
-                    obj.size,
-                    size
-                );
-
-                let prefer_local = match last_modified {
-                    Some(last_modified) if !self.opts.size_only => {
-                        let cloud_last_modified =
-                            OffsetDateTime::parse(&obj.last_modified, &Rfc3339).unwrap();
-                        let local_last_modified = last_modified;
-                        log::debug!("{path:?} last modified: local({local_last_modified}) > cloud({cloud_last_modified}) = {}", local_last_modified > cloud_last_modified);
-                        obj.size == 0 || local_last_modified > cloud_last_modified
+                if obj.size == 0 && !exists {
+                    operations.push(Operation::WriteEmpty(path));
+                } else {
+                    operations.push(Operation::Checked(path))
+                }
+                continue;
+            }
+
+            log::debug!(
+                "{:?} has different size, cloud({}) != local({})",
+                path,
+                obj.size,
+                size
+            );
+
+            let prefer_local = match last_modified {
+                Some(last_modified) if !self.opts.size_only => {
+                    let cloud_last_modified =
+                        OffsetDateTime::parse(&obj.last_modified, &Rfc3339).unwrap();
+                    let local_last_modified = last_modified;
+                    log::debug!("{path:?} last modified: local({local_last_modified}) > cloud({cloud_last_modified}) = {}", local_last_modified > cloud_last_modified);
+
+                    if let Some(state) = SYNCED_STATES.inner.get(&obj.key) {
+                        if state.conflicts(
+                            size,
+                            local_last_modified.unix_timestamp(),
+                            obj.size,
+                            cloud_last_modified.unix_timestamp(),
+                        ) {
+                            operations.push(Operation::Conflict(path.clone()));
+                        }
                     }
-                    _ => obj.size == 0,
-                };
 
-                if prefer_local {
-                    log::debug!("Preferring local {path:?} instead of cloud version");
-                    operations.push(Operation::Upload(path));
-                } else {
-                    operations.push(Operation::Write(path));
+                    obj.size == 0 || local_last_modified > cloud_last_modified
                 }
+                _ => obj.size == 0,
+            };
+
+            if prefer_local {
+                log::debug!("Preferring local {path:?} instead of cloud version");
+                operations.push(Operation::Upload(path));
+            } else {
+                operations.push(Operation::Write(path, obj.size));
             }
         }
 
+        for key in tombstones.iter().filter(|key| is_selected(key)) {
+            operations.push(Operation::Deleted(key_to_path(&key)));
+        }
+
         Ok(operations)
     }
 
     async fn download(&self, path: &str) -> Result<Vec<u8>> {
         let res = self.bucket.get_object(path).await?;
+        check_response(&res)?;
         Ok(res.bytes().into())
     }
 
@@ -111,22 +269,125 @@ impl Backend for S3 {
 
     async fn remove(&self, path: &str) -> Result<()> {
         if self.opts.move_to_trash {
-            self.bucket
-                .copy_object_internal(path, TRASH_PATH.to_owned() + path)
-                .await?;
+            check_status(
+                self.bucket
+                    .copy_object_internal(path, TRASH_PATH.to_owned() + path)
+                    .await?,
+            )?;
         }
-        self.bucket.delete_object(path).await?;
-        Ok(())
+        check_response(&self.bucket.delete_object(path).await?)?;
+        self.write_tombstone(path).await
     }
 
     async fn upload(&self, path: &str, content: &[u8]) -> Result<()> {
-        self.bucket.put_object(path, content).await?;
+        check_response(&self.bucket.put_object(path, content).await?)?;
         Ok(())
     }
 
     async fn rename(&self, from: &str, to: &str) -> Result<()> {
-        self.bucket.copy_object_internal(from, to).await?;
-        self.bucket.delete_object(from).await?;
+        check_status(self.bucket.copy_object_internal(from, to).await?)?;
+        check_response(&self.bucket.delete_object(from).await?)?;
+        self.write_tombstone(from).await
+    }
+
+    async fn acquire_lease(&self, device: &str, ttl: Duration) -> Result<Option<Lease>> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+
+        if let Some(lease) = self.read_lease().await? {
+            if lease.device != device && lease.expires_at > now {
+                return Ok(Some(lease));
+            }
+        }
+
+        let lease = Lease {
+            device: device.to_owned(),
+            expires_at: now + ttl.as_secs() as i64,
+        };
+
+        self.write_json(LEASE_PATH, &lease).await?;
+
+        // There are no conditional writes, so make sure no other device won the race
+        sleep(LEASE_SETTLE_DELAY).await;
+
+        match self.read_lease().await? {
+            Some(lease) if lease.device != device => Ok(Some(lease)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn release_lease(&self, device: &str) -> Result<()> {
+        if let Some(lease) = self.read_lease().await? {
+            if lease.device == device {
+                check_response(&self.bucket.delete_object(LEASE_PATH).await?)?;
+            }
+        }
+
         Ok(())
     }
+
+    async fn devices(&self) -> Result<Vec<Device>> {
+        Ok(self.read_json(DEVICES_PATH).await?.unwrap_or_default())
+    }
+
+    async fn register_device(&self, device: Device) -> Result<()> {
+        let mut devices = self.devices().await?;
+        devices.retain(|x| x.id != device.id);
+        devices.push(device);
+        self.write_json(DEVICES_PATH, &devices).await
+    }
+
+    async fn upload_stream(
+        &self,
+        path: &str,
+        mut reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> Result<()> {
+        check_status(self.bucket.put_object_stream(&mut reader, path).await?)
+    }
+
+    async fn download_stream(
+        &self,
+        path: &str,
+        mut writer: &mut (dyn AsyncWrite + Send + Unpin),
+    ) -> Result<()> {
+        check_status(self.bucket.get_object_stream(path, &mut writer).await?)
+    }
+
+    async fn last_writer(&self, path: &str) -> Result<Option<String>> {
+        let (head, code) = self.bucket.head_object(path).await?;
+
+        if code == 404 {
+            return Ok(None);
+        }
+
+        check_status(code)?;
+
+        Ok(head
+            .metadata
+            .and_then(|metadata| metadata.get(DEVICE_METADATA).cloned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_fields_of_an_error_body() {
+        let body = "<?xml version=\"1.0\"?><Error><Code>QuotaExceeded</Code>\
+                    <Message>The bucket is full</Message></Error>";
+
+        assert_eq!(xml_field(body, "Code"), Some("QuotaExceeded"));
+        assert_eq!(xml_field(body, "Message"), Some("The bucket is full"));
+        assert_eq!(xml_field(body, "Resource"), None);
+    }
+
+    #[test]
+    fn tombstones_expire_after_the_retention() {
+        let now = OffsetDateTime::parse("2024-03-31T12:00:00Z", &Rfc3339).unwrap();
+
+        assert!(!is_expired("2024-03-02T12:00:00Z", now, 30));
+        assert!(is_expired("2024-03-01T11:59:59Z", now, 30));
+        assert!(!is_expired("2020-01-01T00:00:00Z", now, 0));
+        assert!(!is_expired("not a time", now, 30));
+    }
 }
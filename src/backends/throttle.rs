@@ -0,0 +1,350 @@
+use super::interface::*;
+use crate::util::{activity, config::CONFIG, queue::Change};
+use futures::future::BoxFuture;
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::Mutex,
+    time::{sleep, Instant},
+};
+
+/// The longest sleep before checking the rate again, so runtime changes apply quickly
+const MAX_WAIT: Duration = Duration::from_secs(1);
+/// Bytes paid for at once, so a transfer is paced all along instead of once per file
+const CHUNK_SIZE: usize = 64 * 1024;
+
+lazy_static! {
+    pub static ref UPLOAD_LIMIT: TokenBucket = TokenBucket::new(CONFIG.max_upload_rate);
+    pub static ref DOWNLOAD_LIMIT: TokenBucket = TokenBucket::new(CONFIG.max_download_rate);
+}
+
+/// Limits the bytes per second shared by all the transfers using it, 0 means unlimited
+pub struct TokenBucket {
+    rate: AtomicU64,
+    /// Available bytes (negative while in debt) and when they were last refilled,
+    /// the lock also makes the waiting transfers take turns
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: AtomicU64::new(rate),
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Waits until the transfer of `bytes` fits in the rate
+    pub async fn take(&self, bytes: usize) {
+        let mut state = self.state.lock().await;
+        let refill = |(tokens, updated): &mut (f64, Instant), rate: u64| {
+            *tokens = (*tokens + updated.elapsed().as_secs_f64() * rate as f64).min(rate as f64);
+            *updated = Instant::now();
+        };
+
+        refill(&mut state, self.rate());
+        state.0 -= bytes as f64;
+
+        while state.0 < 0.0 {
+            let rate = self.rate();
+
+            if rate == 0 {
+                state.0 = 0.0;
+                break;
+            }
+
+            sleep(Duration::from_secs_f64(-state.0 / rate as f64).min(MAX_WAIT)).await;
+            refill(&mut state, rate);
+        }
+    }
+}
+
+/// Pays for the chunks of a transfer before they go through
+struct Paced<T> {
+    inner: T,
+    limit: &'static TokenBucket,
+    /// Size of the chunk being paid for and the wait for its tokens
+    pending: Option<(usize, BoxFuture<'static, ()>)>,
+    /// Counts the bytes gone through, for the dashboard
+    progress: Option<Arc<AtomicU64>>,
+}
+
+impl<T> Paced<T> {
+    fn new(inner: T, limit: &'static TokenBucket) -> Self {
+        Self {
+            inner,
+            limit,
+            pending: None,
+            progress: None,
+        }
+    }
+
+    /// A retried transfer starts over, so does its progress
+    fn reporting_to(self, progress: Option<Arc<AtomicU64>>) -> Self {
+        if let Some(progress) = &progress {
+            progress.store(0, Ordering::Relaxed);
+        }
+
+        Self { progress, ..self }
+    }
+
+    fn report(&self, size: usize) {
+        if let Some(progress) = &self.progress {
+            progress.fetch_add(size as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Waits for the tokens of up to `wanted` bytes, returns how many were paid for
+    fn poll_paid(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<usize> {
+        let limit = self.limit;
+        let (size, wait) = self.pending.get_or_insert_with(|| {
+            let size = wanted.min(CHUNK_SIZE);
+            (size, Box::pin(limit.take(size)))
+        });
+
+        ready!(wait.as_mut().poll(cx));
+
+        let size = *size;
+        self.pending = None;
+        Poll::Ready(size.min(wanted))
+    }
+}
+
+impl AsyncRead for Paced<&[u8]> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let wanted = buf.remaining().min(this.inner.len());
+
+        if wanted == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let size = ready!(this.poll_paid(cx, wanted));
+        let (chunk, rest) = this.inner.split_at(size);
+
+        buf.put_slice(chunk);
+        this.inner = rest;
+        this.report(size);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Paced<Vec<u8>> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let size = ready!(this.poll_paid(cx, buf.len()));
+
+        this.inner.extend_from_slice(&buf[..size]);
+        this.report(size);
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Wraps a backend and limits its upload and download rates
+pub struct Throttled<B> {
+    inner: B,
+}
+
+#[async_trait]
+impl<B: Backend + Send + Sync> Backend for Throttled<B> {
+    async fn init(options: BackendOptions) -> Self {
+        Self {
+            inner: B::init(options).await,
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<RemoteObject>> {
+        self.inner.list(prefix).await
+    }
+
+    async fn restore(&self, path: &str) -> Result<()> {
+        self.inner.restore(path).await
+    }
+
+    async fn remove(&self, path: &str) -> Result<()> {
+        self.inner.remove(path).await
+    }
+
+    async fn download(&self, path: &str) -> Result<Vec<u8>> {
+        // Streamed even while unlimited, so the progress is reported
+        let mut writer = Paced::new(vec![], &DOWNLOAD_LIMIT)
+            .reporting_to(activity::progress(path, Change::Download));
+        self.inner.download_stream(path, &mut writer).await?;
+        Ok(writer.inner)
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool> {
+        self.inner.exists(path).await
+    }
+
+    async fn rename(&self, old_path: &str, path: &str) -> Result<()> {
+        self.inner.rename(old_path, path).await
+    }
+
+    async fn sync(&self, dry_run: bool) -> Result<Vec<Operation>> {
+        self.inner.sync(dry_run).await
+    }
+
+    async fn upload(&self, path: &str, content: &[u8]) -> Result<()> {
+        let mut reader = Paced::new(content, &UPLOAD_LIMIT)
+            .reporting_to(activity::progress(path, Change::Upload));
+
+        self.inner.upload_stream(path, &mut reader).await
+    }
+
+    async fn acquire_lease(&self, device: &str, ttl: Duration) -> Result<Option<Lease>> {
+        self.inner.acquire_lease(device, ttl).await
+    }
+
+    async fn release_lease(&self, device: &str) -> Result<()> {
+        self.inner.release_lease(device).await
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>> {
+        self.inner.devices().await
+    }
+
+    async fn register_device(&self, device: Device) -> Result<()> {
+        self.inner.register_device(device).await
+    }
+
+    async fn last_writer(&self, path: &str) -> Result<Option<String>> {
+        self.inner.last_writer(path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::{self, FakeCloud};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test(start_paused = true)]
+    async fn take_waits_for_the_rate() {
+        let bucket = TokenBucket::new(1000);
+        let started_at = Instant::now();
+
+        // Starts full
+        bucket.take(1000).await;
+        assert_eq!(started_at.elapsed(), Duration::ZERO);
+
+        bucket.take(2000).await;
+        assert!(started_at.elapsed() >= Duration::from_secs(2));
+        assert!(started_at.elapsed() < Duration::from_millis(2100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited_never_waits() {
+        let bucket = TokenBucket::new(0);
+        let started_at = Instant::now();
+
+        bucket.take(usize::MAX / 2).await;
+        bucket.take(usize::MAX / 2).await;
+
+        assert_eq!(started_at.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_changes_apply_to_waiting_transfers() {
+        let bucket = TokenBucket::new(100);
+        let started_at = Instant::now();
+
+        bucket.take(100).await;
+        bucket.set_rate(0);
+        bucket.take(10_000).await;
+
+        assert_eq!(started_at.elapsed(), Duration::ZERO);
+    }
+
+    lazy_static! {
+        static ref LIMIT: TokenBucket = TokenBucket::new(CHUNK_SIZE as u64);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transfers_are_paced_per_chunk() {
+        let content = (0..3 * CHUNK_SIZE).map(|x| x as u8).collect::<Vec<_>>();
+        let started_at = Instant::now();
+        let mut read = vec![];
+
+        Paced::new(&content[..], &LIMIT)
+            .read_to_end(&mut read)
+            .await
+            .unwrap();
+
+        // The first chunk is paid by the full bucket, each of the next ones takes a second
+        assert_eq!(read, content);
+        assert!(started_at.elapsed() >= Duration::from_secs(2));
+        assert!(started_at.elapsed() < Duration::from_millis(2100));
+
+        let mut writer = Paced::new(vec![], &LIMIT);
+        writer.write_all(&content).await.unwrap();
+
+        assert_eq!(writer.inner, content);
+        assert!(started_at.elapsed() >= Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn transfers_report_their_progress() {
+        testing::init();
+
+        let cloud = Throttled {
+            inner: FakeCloud::default(),
+        };
+        let content = vec![0; 3 * CHUNK_SIZE];
+        let _transfer = activity::start("progress", Change::Upload, content.len() as u64);
+
+        cloud.upload("progress", &content).await.unwrap();
+
+        let transfer = activity::active()
+            .into_iter()
+            .find(|x| x.key == "progress")
+            .unwrap();
+        assert_eq!(transfer.transferred, transfer.size);
+
+        // Attempts start over
+        cloud.upload("progress", &content).await.unwrap();
+
+        let transfer = activity::active()
+            .into_iter()
+            .find(|x| x.key == "progress")
+            .unwrap();
+        assert_eq!(transfer.transferred, transfer.size);
+    }
+}
@@ -0,0 +1,192 @@
+use super::interface::*;
+use crate::util::{activity::*, audit::AuditEntry, notification, queue::Change};
+use std::time::Instant;
+
+/// Wraps a backend and records its transfers and counters for the dashboard,
+/// It goes outside of the retries so only the final result is counted
+pub struct Tracked<B> {
+    inner: B,
+    provider: &'static str,
+}
+
+impl<B> Tracked<B> {
+    fn finish<T>(
+        &self,
+        key: &str,
+        action: Action,
+        bytes: u64,
+        started_at: Instant,
+        result: Result<T>,
+    ) -> Result<T> {
+        let op = format!("{action:?}").to_lowercase();
+        let duration = started_at.elapsed().as_millis() as u64;
+
+        count_operation(self.provider, action, result.is_ok());
+
+        if let Err(err) = &result {
+            let error = format!("{err:#}");
+            // Retries and failures are reported by the callers
+            log::debug!(path = key, op = op.as_str(), backend = self.provider, bytes, duration, error = error.as_str(); "Couldn't {op} {key}");
+            count(self.provider, |stats| stats.errors += 1);
+            notification::transfer_failed(key, err);
+            record(key, action, Some(error));
+        } else {
+            let done = match action {
+                Action::Upload => "Uploaded",
+                Action::Download => "Downloaded",
+                Action::Remove | Action::RemoveLocal => "Removed",
+                Action::Rename | Action::Conflict => "Renamed",
+            };
+            log::info!(path = key, op = op.as_str(), backend = self.provider, bytes, duration; "{done} {key}");
+            record(key, action, None);
+            notification::transfer_finished(key, action, bytes);
+        }
+
+        result
+    }
+}
+
+#[async_trait]
+impl<B: Backend + Send + Sync> Backend for Tracked<B> {
+    async fn init(options: BackendOptions) -> Self {
+        Self {
+            provider: options.provider(),
+            inner: B::init(options).await,
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<RemoteObject>> {
+        self.inner.list(prefix).await
+    }
+
+    async fn restore(&self, path: &str) -> Result<()> {
+        self.inner.restore(path).await
+    }
+
+    async fn remove(&self, path: &str) -> Result<()> {
+        let started_at = Instant::now();
+        let result = self.inner.remove(path).await;
+
+        if result.is_ok() {
+            count(self.provider, |stats| stats.removals += 1);
+            AuditEntry::new(Action::Remove, path).save();
+        }
+
+        self.finish(path, Action::Remove, 0, started_at, result)
+    }
+
+    async fn download(&self, path: &str) -> Result<Vec<u8>> {
+        let _transfer = start(path, Change::Download, expected_size(path));
+        let started_at = Instant::now();
+        let result = self.inner.download(path).await;
+        let bytes = result.as_ref().map(|x| x.len() as u64).unwrap_or(0);
+
+        if let Ok(buffer) = &result {
+            count(self.provider, |stats| {
+                stats.downloads += 1;
+                stats.downloaded_bytes += bytes;
+            });
+            AuditEntry::new(Action::Download, path)
+                .content(buffer)
+                .save();
+        }
+
+        self.finish(path, Action::Download, bytes, started_at, result)
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool> {
+        self.inner.exists(path).await
+    }
+
+    async fn rename(&self, old_path: &str, path: &str) -> Result<()> {
+        let started_at = Instant::now();
+        let result = self.inner.rename(old_path, path).await;
+
+        if result.is_ok() {
+            AuditEntry::new(Action::Rename, path).from(old_path).save();
+        }
+
+        self.finish(path, Action::Rename, 0, started_at, result)
+    }
+
+    async fn sync(&self, dry_run: bool) -> Result<Vec<Operation>> {
+        self.inner.sync(dry_run).await
+    }
+
+    async fn upload(&self, path: &str, content: &[u8]) -> Result<()> {
+        let bytes = content.len() as u64;
+        let _transfer = start(path, Change::Upload, bytes);
+        let started_at = Instant::now();
+        let result = self.inner.upload(path, content).await;
+
+        if result.is_ok() {
+            count(self.provider, |stats| {
+                stats.uploads += 1;
+                stats.uploaded_bytes += bytes;
+            });
+            AuditEntry::new(Action::Upload, path)
+                .content(content)
+                .save();
+        }
+
+        self.finish(path, Action::Upload, bytes, started_at, result)
+    }
+
+    async fn acquire_lease(&self, device: &str, ttl: Duration) -> Result<Option<Lease>> {
+        self.inner.acquire_lease(device, ttl).await
+    }
+
+    async fn release_lease(&self, device: &str) -> Result<()> {
+        self.inner.release_lease(device).await
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>> {
+        self.inner.devices().await
+    }
+
+    async fn register_device(&self, device: Device) -> Result<()> {
+        self.inner.register_device(device).await
+    }
+
+    async fn last_writer(&self, path: &str) -> Result<Option<String>> {
+        self.inner.last_writer(path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::Retrying;
+    use crate::util::testing::{self, FakeCloud};
+    use std::sync::atomic::Ordering;
+
+    #[tokio::test]
+    async fn retried_failures_are_not_counted() {
+        testing::init();
+
+        let cloud = Tracked {
+            inner: Retrying {
+                inner: FakeCloud::default(),
+            },
+            provider: "retried",
+        };
+
+        cloud.inner.inner.failures.store(1, Ordering::Relaxed);
+        cloud.upload("flaky", b"content").await.unwrap();
+
+        assert_eq!(cloud.inner.inner.calls(), ["upload flaky", "upload flaky"]);
+        assert_eq!(
+            operations().get(&("retried".to_owned(), Action::Upload, false)),
+            None
+        );
+        assert_eq!(
+            operations().get(&("retried".to_owned(), Action::Upload, true)),
+            Some(&1)
+        );
+        assert_eq!(stats()["retried"].errors, 0);
+        assert!(recent()
+            .iter()
+            .filter(|x| x.key == "flaky")
+            .all(|x| x.error.is_none()));
+    }
+}
@@ -0,0 +1,433 @@
+use crate::backends::*;
+use crate::control::{self, Request, Response};
+use crate::util::{config::*, device::*, *};
+use crate::{
+    daemon, dashboard, sync::*, DEAD_LETTERS, IS_INTERNET_AVAILABLE, PENDING_CHANGES, SYNCED_PATHS,
+};
+use anyhow::{anyhow, bail};
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+use serde_json::json;
+use std::{collections::HashMap, fs, process, str::FromStr};
+
+/// Exit codes of `sync --once`, any other error exits with 1
+pub const EXIT_PARTIAL_FAILURE: i32 = 2;
+pub const EXIT_CONFLICTS: i32 = 3;
+pub const EXIT_CONFIG_ERROR: i32 = 4;
+/// Offline, or another device holds the bucket lease
+pub const EXIT_UNAVAILABLE: i32 = 5;
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Simple utility to backup/sync data between devices to the cloud"
+)]
+pub struct Cli {
+    /// Use this settings file instead of the default one
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the sync daemon (default)
+    Run,
+    /// Sync with the cloud
+    Sync {
+        /// Run a single sync pass and exit
+        #[arg(long)]
+        once: bool,
+        /// Print the planned operations without applying them
+        #[arg(long, conflicts_with = "once")]
+        dry_run: bool,
+        /// Print the plan as JSON
+        #[arg(long, requires = "dry_run")]
+        json: bool,
+        /// Ask the running daemon to sync now
+        #[arg(long, conflicts_with_all = ["once", "dry_run"])]
+        now: bool,
+    },
+    /// Pause the running daemon, Local changes are queued meanwhile
+    Pause,
+    /// Resume the running daemon
+    Resume,
+    /// List the queued and failed changes of the running daemon
+    Pending,
+    /// Queue the failed changes of the running daemon again
+    Retry,
+    /// Show a dashboard of the running daemon
+    Tui,
+    /// Show the audit log of the applied operations
+    Log {
+        /// Only show the operations touching this path (or anything under it)
+        #[arg(long)]
+        path: Option<String>,
+    },
+    /// Change the transfer rate limits of the running daemon
+    Limit {
+        /// Upload bytes per second, 0 means unlimited
+        #[arg(long)]
+        upload: Option<u64>,
+        /// Download bytes per second, 0 means unlimited
+        #[arg(long)]
+        download: Option<u64>,
+    },
+    /// Show the local sync state
+    Status,
+    /// List the files in the cloud
+    Ls {
+        /// Only list the files under this prefix
+        prefix: Option<String>,
+    },
+    /// Move a removed file back from the trash
+    Restore { path: String },
+    /// List the removed files kept in the trash
+    Trash,
+    /// Compare the local files with the cloud
+    Verify,
+    /// List the devices syncing with the bucket
+    Devices,
+    /// Manage the settings file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Validate the settings file
+    Check,
+}
+
+pub async fn execute(command: Command) -> Result<()> {
+    match command {
+        Command::Sync {
+            dry_run: true,
+            json,
+            ..
+        } => dry_run(json).await,
+        Command::Sync { once: true, .. } => sync_once().await,
+        Command::Sync { now: true, .. } => control(Request::SyncNow).await,
+        Command::Pause => control(Request::Pause).await,
+        Command::Resume => control(Request::Resume).await,
+        Command::Pending => control(Request::Pending).await,
+        Command::Retry => control(Request::RetryFailed).await,
+        Command::Tui => dashboard::run().await,
+        Command::Log { path } => log(path),
+        Command::Limit { upload, download } => control(Request::Limit { upload, download }).await,
+        Command::Run | Command::Sync { .. } => daemon::run().await,
+        Command::Status => status().await,
+        Command::Ls { prefix } => ls(prefix.unwrap_or_default()).await,
+        Command::Restore { path } => restore(&path).await,
+        Command::Trash => trash().await,
+        Command::Verify => verify().await,
+        Command::Devices => devices().await,
+        Command::Config { .. } => check_config(),
+    }
+}
+
+pub fn check_config() -> Result<()> {
+    validate_config()?;
+    println!("Settings file {:?} is valid", settings_file_path());
+    Ok(())
+}
+
+/// Loads the settings file without touching CONFIG, which panics on invalid settings
+pub fn validate_config() -> Result<Config> {
+    let config = load_config()?;
+
+    LevelFilter::from_str(&config.log).map_err(|_| anyhow!("Invalid log level: {}", config.log))?;
+
+    if !config.path.is_dir() {
+        bail!("Syncing directory {:?} doesn't exist", config.path);
+    }
+
+    Ok(config)
+}
+
+async fn sync_once() -> Result<()> {
+    let lock = lock::acquire()?;
+
+    check_connectivity().await;
+
+    if !*IS_INTERNET_AVAILABLE.lock().unwrap() {
+        eprintln!("There are no internet connection");
+        process::exit(EXIT_UNAVAILABLE);
+    }
+
+    let cloud = init_backend(CONFIG.backend.clone()).await;
+    let report = sync_pass(&cloud).await;
+
+    webhook::flush().await;
+
+    let report = report?;
+
+    PENDING_CHANGES.save()?;
+    drop(lock);
+
+    if report.skipped {
+        eprintln!("Another device is syncing, Try again later");
+        process::exit(EXIT_UNAVAILABLE);
+    }
+
+    println!("{} file(s) synced", report.synced);
+
+    if report.deferred > 0 {
+        println!("{} file(s) deferred to their sync window", report.deferred);
+    }
+
+    if report.interrupted {
+        bail!("Sync was interrupted");
+    }
+
+    if report.failed > 0 {
+        eprintln!("{} change(s) failed, See `rsink status`", report.failed);
+        process::exit(EXIT_PARTIAL_FAILURE);
+    }
+
+    if report.conflicts + report.refused > 0 {
+        eprintln!(
+            "{} conflict(s) and {} refused removal(s)",
+            report.conflicts, report.refused
+        );
+        process::exit(EXIT_CONFLICTS);
+    }
+
+    Ok(())
+}
+
+async fn dry_run(json: bool) -> Result<()> {
+    let cloud = init_backend(CONFIG.backend.clone()).await;
+    let plan = plan(&cloud, true).await?;
+    let describe = |op: &Operation| match op {
+        Operation::Upload(_) => Some("upload"),
+        Operation::Write(..) | Operation::WriteEmpty(_) => Some("download"),
+        Operation::Remove(_) => Some("remove_cloud"),
+        Operation::RemoveLocal(_) => Some("remove_local"),
+        Operation::Conflict(_) => Some("conflict"),
+        Operation::Checked(_) | Operation::Deleted(_) => None,
+    };
+    let entries = |ops: &[Operation]| {
+        ops.iter()
+            .filter_map(|op| Some((describe(op)?, normalize_path(&op.path()))))
+            .collect::<Vec<_>>()
+    };
+    let operations = entries(&plan.operations);
+    let refused = entries(&plan.refused);
+    let pending = PENDING_CHANGES.snapshot();
+
+    if json {
+        let to_json = |entries: Vec<(&str, String)>| {
+            entries
+                .into_iter()
+                .map(|(op, path)| json!({ "op": op, "path": path }))
+                .collect::<Vec<_>>()
+        };
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&json!({
+                "pending": pending,
+                "operations": to_json(operations),
+                "refused": to_json(refused),
+            }))?
+        );
+        return Ok(());
+    }
+
+    for (key, change) in &pending {
+        println!("{:<14}{key}", format!("pending_{change:?}").to_lowercase());
+    }
+
+    for (op, path) in &operations {
+        println!("{op:<14}{path}");
+    }
+
+    for (op, path) in &refused {
+        println!("{op:<14}{path}  (refused by the delete threshold)");
+    }
+
+    if pending.is_empty() && operations.is_empty() && refused.is_empty() {
+        println!("Everything is in sync");
+    }
+
+    Ok(())
+}
+
+/// Sends a command to the running daemon and prints its response
+async fn control(request: Request) -> Result<()> {
+    match control::send(request).await? {
+        Response::Done { message } => println!("{message}"),
+        Response::Error { message } => bail!(message),
+        Response::Changes { pending, failed } => {
+            for (key, change) in pending {
+                println!("pending  {:<10}{key}", format!("{change:?}").to_lowercase());
+            }
+            for (key, change) in failed {
+                println!("failed   {:<10}{key}", format!("{change:?}").to_lowercase());
+            }
+        }
+        response => println!("{response:?}"),
+    }
+
+    Ok(())
+}
+
+async fn status() -> Result<()> {
+    println!("Device:     {} ({})", *DEVICE_NAME, *DEVICE_ID);
+    println!("Settings:   {:?}", settings_file_path());
+    println!("Directory:  {:?}", CONFIG.path);
+    println!("Mode:       {:?}", CONFIG.mode);
+
+    // The daemon keeps the counters in memory, prefer its view when it's running
+    if let Ok(Response::Status(status)) = control::send(Request::Status).await {
+        println!(
+            "Daemon:     running, {} (pid {})",
+            status.state(),
+            status.pid
+        );
+        println!("Synced:     {} file(s)", status.synced);
+        println!("Pending:    {} change(s)", status.pending);
+        println!("Failed:     {} change(s)", status.failed);
+        println!(
+            "Limits:     upload {}, download {}",
+            format_rate(status.max_upload_rate),
+            format_rate(status.max_download_rate)
+        );
+        return Ok(());
+    }
+
+    match lock::holder() {
+        Some(owner) => println!("Daemon:     running ({owner})"),
+        None => println!("Daemon:     not running"),
+    }
+
+    println!("Synced:     {} file(s)", SYNCED_PATHS.inner.len());
+    println!("Pending:    {} change(s)", PENDING_CHANGES.inner.len());
+    println!("Failed:     {} change(s)", DEAD_LETTERS.inner.len());
+
+    Ok(())
+}
+
+async fn ls(prefix: String) -> Result<()> {
+    let cloud = init_backend(CONFIG.backend.clone()).await;
+
+    for obj in cloud.list(&prefix).await? {
+        if !is_internal(&obj.key) {
+            println!("{:>12}  {}  {}", obj.size, obj.last_modified, obj.key);
+        }
+    }
+
+    Ok(())
+}
+
+async fn trash() -> Result<()> {
+    let cloud = init_backend(CONFIG.backend.clone()).await;
+
+    for obj in cloud.list(TRASH_PATH).await? {
+        let key = obj.key.strip_prefix(TRASH_PATH).unwrap_or(&obj.key);
+        println!("{:>12}  {}  {}", obj.size, obj.last_modified, key);
+    }
+
+    Ok(())
+}
+
+async fn restore(path: &str) -> Result<()> {
+    let cloud = init_backend(CONFIG.backend.clone()).await;
+    let key = path.trim_start_matches('/');
+    let key = key.strip_prefix(TRASH_PATH).unwrap_or(key);
+
+    cloud.restore(key).await?;
+
+    println!("Restored {key}, It will be downloaded on the next sync");
+
+    Ok(())
+}
+
+async fn verify() -> Result<()> {
+    let cloud = init_backend(CONFIG.backend.clone()).await;
+    let remote = cloud
+        .list("")
+        .await?
+        .into_iter()
+        .filter(|obj| !is_internal(&obj.key) && is_selected(&obj.key))
+        .map(|obj| (obj.key, obj.size))
+        .collect::<HashMap<_, _>>();
+    let mut differences = 0;
+
+    for (key, size) in &remote {
+        match fs::metadata(key_to_path(key)) {
+            Ok(m) if m.len() == *size => {}
+            Ok(m) => {
+                println!("Different size: {key} local({}) != cloud({size})", m.len());
+                differences += 1;
+            }
+            Err(_) => {
+                println!("Missing locally: {key}");
+                differences += 1;
+            }
+        }
+    }
+
+    for entry in walk_dir(&CONFIG.path)? {
+        let key = normalize_path(&entry.path());
+
+        if is_selected(&key) && !remote.contains_key(&key) {
+            println!("Missing in the cloud: {key}");
+            differences += 1;
+        }
+    }
+
+    if differences > 0 {
+        bail!("{differences} difference(s) found");
+    }
+
+    println!("Everything is in sync");
+
+    Ok(())
+}
+
+fn log(path: Option<String>) -> Result<()> {
+    let path = path.map(|x| x.trim_matches('/').to_owned());
+
+    for entry in audit::entries(path.as_deref())? {
+        let action = serde_json::to_value(entry.action)?;
+        let action = action.as_str().unwrap_or_default();
+        let path = match &entry.from {
+            Some(from) => format!("{from} -> {}", entry.path),
+            None => entry.path,
+        };
+        let content = match (entry.size, entry.hash) {
+            (Some(size), Some(hash)) => format!(" ({size} bytes, {hash})"),
+            _ => String::new(),
+        };
+        let note = entry.note.map(|x| format!(", {x}")).unwrap_or_default();
+
+        println!(
+            "{}  {:<12}{path}{content}  by {} via {}{note}",
+            entry.at, action, entry.device_name, entry.origin
+        );
+    }
+
+    Ok(())
+}
+
+async fn devices() -> Result<()> {
+    let cloud = init_backend(CONFIG.backend.clone()).await;
+
+    for device in cloud.devices().await? {
+        let current = if device.id == *DEVICE_ID {
+            " (this device)"
+        } else {
+            ""
+        };
+        println!(
+            "{}  {}  last seen {}{}",
+            device.id, device.name, device.last_seen, current
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,281 @@
+use crate::backends::{DOWNLOAD_LIMIT, UPLOAD_LIMIT};
+use crate::util::{activity::*, cache::cache_path, device::*, queue::Change, schedule, *};
+use crate::{DEAD_LETTERS, IS_INTERNET_AVAILABLE, PAUSED, PENDING_CHANGES, SYNCED_PATHS, SYNCING};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf};
+use tokio::sync::Notify;
+
+lazy_static! {
+    static ref SYNC_NOW: Notify = Notify::new();
+}
+
+/// Commands accepted by the daemon on the control socket, one JSON object per line
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Dashboard,
+    Pause,
+    Resume,
+    SyncNow,
+    Pending,
+    RetryFailed,
+    /// Changes the bytes per second limits, 0 means unlimited
+    Limit {
+        upload: Option<u64>,
+        download: Option<u64>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Status(DaemonStatus),
+    Dashboard(Box<Dashboard>),
+    Changes {
+        pending: BTreeMap<String, Change>,
+        failed: BTreeMap<String, Change>,
+    },
+    Done {
+        message: String,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub online: bool,
+    pub syncing: bool,
+    pub paused: bool,
+    /// Whether the sync schedule allows syncing now
+    pub scheduled: bool,
+    pub max_upload_rate: u64,
+    pub max_download_rate: u64,
+    pub synced: usize,
+    pub pending: usize,
+    pub failed: usize,
+}
+
+/// Everything `rsink tui` shows
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Dashboard {
+    pub device: String,
+    pub status: DaemonStatus,
+    /// Operations done and planned by the running sync pass
+    pub pass: Option<(usize, usize)>,
+    pub active: Vec<Transfer>,
+    pub pending: BTreeMap<String, Change>,
+    pub failed: BTreeMap<String, Change>,
+    pub recent: Vec<Entry>,
+    pub stats: BTreeMap<String, Stats>,
+}
+
+impl DaemonStatus {
+    pub fn state(&self) -> &'static str {
+        match (self.paused, self.scheduled, self.syncing, self.online) {
+            (true, ..) => "paused",
+            (_, false, ..) => "outside of the sync schedule",
+            (_, _, true, _) => "syncing",
+            (.., true) => "idle",
+            _ => "offline",
+        }
+    }
+}
+
+pub fn socket_path() -> PathBuf {
+    cache_path("control.sock")
+}
+
+/// Resolves once a sync was requested through the control socket
+pub async fn sync_requested() {
+    SYNC_NOW.notified().await
+}
+
+pub fn request_sync() {
+    SYNC_NOW.notify_one();
+}
+
+fn status() -> DaemonStatus {
+    DaemonStatus {
+        pid: std::process::id(),
+        online: *IS_INTERNET_AVAILABLE.lock().unwrap(),
+        syncing: *SYNCING.lock().unwrap(),
+        paused: *PAUSED.lock().unwrap(),
+        scheduled: schedule::is_open(),
+        max_upload_rate: UPLOAD_LIMIT.rate(),
+        max_download_rate: DOWNLOAD_LIMIT.rate(),
+        synced: SYNCED_PATHS.inner.len(),
+        pending: PENDING_CHANGES.inner.len(),
+        failed: DEAD_LETTERS.inner.len(),
+    }
+}
+
+fn handle(request: Request) -> Response {
+    let done = |message: &str| Response::Done {
+        message: message.to_owned(),
+    };
+
+    match request {
+        Request::Status => Response::Status(status()),
+        Request::Dashboard => Response::Dashboard(Box::new(Dashboard {
+            device: format!("{} ({})", *DEVICE_NAME, *DEVICE_ID),
+            status: status(),
+            pass: pass_progress(),
+            active: active(),
+            pending: PENDING_CHANGES.snapshot(),
+            failed: DEAD_LETTERS.snapshot(),
+            recent: recent(),
+            stats: stats(),
+        })),
+        Request::Pause => {
+            *PAUSED.lock().unwrap() = true;
+            log::info!("Syncing paused, Local changes will be queued");
+            done("Syncing paused")
+        }
+        Request::Resume => {
+            *PAUSED.lock().unwrap() = false;
+            log::info!("Syncing resumed");
+            request_sync();
+            done("Syncing resumed")
+        }
+        Request::SyncNow if *PAUSED.lock().unwrap() => Response::Error {
+            message: "Syncing is paused".to_owned(),
+        },
+        Request::SyncNow if !schedule::is_open() => Response::Error {
+            message: "It's outside of the sync schedule".to_owned(),
+        },
+        Request::SyncNow => {
+            request_sync();
+            done("Sync requested")
+        }
+        Request::Limit { upload, download } => {
+            if let Some(rate) = upload {
+                UPLOAD_LIMIT.set_rate(rate);
+            }
+            if let Some(rate) = download {
+                DOWNLOAD_LIMIT.set_rate(rate);
+            }
+            log::info!(
+                "Rate limits: upload {} B/s, download {} B/s",
+                UPLOAD_LIMIT.rate(),
+                DOWNLOAD_LIMIT.rate()
+            );
+            done("Rate limits updated")
+        }
+        Request::Pending => Response::Changes {
+            pending: PENDING_CHANGES.snapshot(),
+            failed: DEAD_LETTERS.snapshot(),
+        },
+        Request::RetryFailed => {
+            let failed = DEAD_LETTERS.snapshot();
+
+            for (key, change) in &failed {
+                PENDING_CHANGES.push(key.clone(), *change);
+            }
+
+            DEAD_LETTERS.inner.clear();
+
+            if let Err(err) = PENDING_CHANGES.save().and_then(|_| DEAD_LETTERS.save()) {
+                return Response::Error {
+                    message: err.to_string(),
+                };
+            }
+
+            request_sync();
+            done(&format!(
+                "{} failed change(s) queued for retry",
+                failed.len()
+            ))
+        }
+    }
+}
+
+/// Accepts control commands until a shutdown is requested
+#[cfg(unix)]
+pub async fn serve() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+
+    let path = socket_path();
+
+    // The instance lock is held, so a leftover socket belongs to a dead daemon
+    std::fs::remove_file(&path).ok();
+
+    let listener = UnixListener::bind(&path)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+
+    log::debug!("Listening for control commands on {path:?}");
+
+    loop {
+        let (stream, _) = tokio::select! {
+            result = listener.accept() => result?,
+            _ = shutdown::requested() => break,
+        };
+
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+
+            while let Ok(Some(line)) = lines.next_line().await {
+                let response = match serde_json::from_str(&line) {
+                    Ok(request) => {
+                        log::debug!("Control command: {request:?}");
+                        handle(request)
+                    }
+                    Err(err) => Response::Error {
+                        message: format!("Invalid command: {err}"),
+                    },
+                };
+
+                let mut buffer = serde_json::to_vec(&response).unwrap();
+                buffer.push(b'\n');
+
+                if writer.write_all(&buffer).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    std::fs::remove_file(&path).ok();
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn serve() -> Result<()> {
+    Ok(())
+}
+
+/// Sends a command to the running daemon
+#[cfg(unix)]
+pub async fn send(request: Request) -> Result<Response> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+
+    let stream = UnixStream::connect(socket_path())
+        .await
+        .map_err(|_| anyhow!("The rsink daemon isn't running"))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut buffer = serde_json::to_vec(&request)?;
+    buffer.push(b'\n');
+    writer.write_all(&buffer).await?;
+
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow!("The rsink daemon closed the connection"))?;
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(not(unix))]
+pub async fn send(_request: Request) -> Result<Response> {
+    Err(anyhow!("The control socket is only supported on unix"))
+}
@@ -0,0 +1,402 @@
+use crate::backends::*;
+use crate::util::{cache::*, config::*, debounce::*, device::*, queue::*, scheduler::*, *};
+use crate::{
+    control, metrics, sync::*, DEAD_LETTERS, IS_INTERNET_AVAILABLE, PAUSED, PENDING_CHANGES,
+    SYNCED_PATHS, SYNCED_STATES, SYNCING,
+};
+use futures::{stream::FuturesUnordered, Future, StreamExt};
+use notify::{event::*, recommended_watcher, RecursiveMode, Watcher};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    fs, spawn,
+    sync::mpsc::channel,
+    task::JoinHandle,
+    time::{interval, sleep, timeout},
+};
+
+/// How long to wait for running transfers on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Waits for the tasks until one fails or `signal` resolves, then calls `stop`
+/// and gives the ones still running `SHUTDOWN_TIMEOUT` to finish
+///
+/// Finished tasks are dropped from the set, e.g. the watcher returns at once in the
+/// download-only modes, so no handle is polled again after its completion
+async fn supervise(
+    tasks: Vec<JoinHandle<Result<()>>>,
+    signal: impl Future<Output = ()>,
+    stop: impl FnOnce(),
+) -> Result<()> {
+    let mut tasks = tasks.into_iter().collect::<FuturesUnordered<_>>();
+
+    tokio::select! {
+        result = async {
+            while let Some(result) = tasks.next().await {
+                result??;
+            }
+            Result::<()>::Ok(())
+        } => result?,
+        _ = signal => log::info!("Shutting down, Waiting for running transfers..."),
+    }
+
+    stop();
+
+    if timeout(SHUTDOWN_TIMEOUT, async {
+        while tasks.next().await.is_some() {}
+    })
+    .await
+    .is_err()
+    {
+        log::warn!("Some transfers didn't finish in time, They will be synced on next start");
+    }
+
+    Ok(())
+}
+
+/// Watches the local directory and syncs with the cloud periodically until a shutdown signal
+pub async fn run() -> Result<()> {
+    let _lock = lock::acquire()?;
+    let cloud_ref = Arc::new(init_backend(CONFIG.backend.clone()).await);
+
+    log::info!("Syncing directory: {:?}", CONFIG.path);
+    log::info!("Syncing delay: {}ms", CONFIG.interval);
+    log::info!("Syncing mode: {:?}", CONFIG.mode);
+    log::info!("Device: {} ({})", *DEVICE_NAME, *DEVICE_ID);
+
+    notification::enable();
+
+    if !DEAD_LETTERS.inner.is_empty() {
+        log::warn!(
+            "{} change(s) kept failing, See {:?}",
+            DEAD_LETTERS.inner.len(),
+            cache_path("dead_letters")
+        );
+    }
+
+    let control_task = spawn(async {
+        if let Err(err) = control::serve().await {
+            log::error!("Control socket error: {err:?}");
+        }
+    });
+
+    let metrics_task = spawn(async {
+        if let Some(address) = &CONFIG.metrics {
+            if let Err(err) = metrics::serve(address).await {
+                log::error!("Metrics listener error: {err:?}");
+            }
+        }
+    });
+
+    let cloud = cloud_ref.clone();
+    let fs_task = spawn(async move {
+        if !CONFIG.mode.can_upload() {
+            log::info!("Local changes won't be watched in {:?} mode", CONFIG.mode);
+            return Ok(());
+        }
+
+        let (tx, mut rx) = channel(100);
+        let mut watcher = recommended_watcher(move |event| {
+            futures::executor::block_on(async { tx.send(event).await.ok() });
+        })?;
+        let mut debouncer = Debouncer::new(Duration::from_millis(CONFIG.debounce));
+        let mut ticker = interval(debouncer.tick_period());
+        let scheduler = Scheduler::new(CONFIG.workers);
+
+        watcher.watch(&CONFIG.path, RecursiveMode::Recursive)?;
+
+        loop {
+            let events = tokio::select! {
+                event = rx.recv() => match event {
+                    Some(Ok(event)) => {
+                        log::debug!("{:?}", event);
+                        debouncer.push(event)
+                    }
+                    Some(Err(e)) => {
+                        log::error!("Notify Error {e}");
+                        continue;
+                    }
+                    None => break,
+                },
+                _ = ticker.tick() => debouncer.flush().await,
+                _ = shutdown::requested() => break,
+            };
+
+            for event in events {
+                if event.paths.iter().all(|path| is_partial(path)) {
+                    continue;
+                }
+
+                if is_expected_change(&event).await {
+                    log::debug!("Ignore event since it was caused by online syncing");
+                    continue;
+                }
+
+                if !*IS_INTERNET_AVAILABLE.lock().unwrap() {
+                    log::warn!("Queue local change.. there are no internet connection");
+                    if is_selected(&normalize_path(&event.paths[0])) {
+                        PENDING_CHANGES.push_event(&event);
+                    }
+                    continue;
+                }
+
+                if *PAUSED.lock().unwrap() {
+                    log::debug!("Queue event since syncing is paused");
+                    if is_selected(&normalize_path(&event.paths[0])) {
+                        PENDING_CHANGES.push_event(&event);
+                    }
+                    continue;
+                }
+
+                if !event.paths.iter().all(|path| schedule::allows_path(path)) {
+                    log::debug!("Queue event since it's outside of the sync schedule");
+                    if is_selected(&normalize_path(&event.paths[0])) {
+                        PENDING_CHANGES.push_event(&event);
+                    }
+                    continue;
+                }
+
+                if *SYNCING.lock().unwrap() {
+                    log::debug!("Queue event since online syncing is working");
+                    if is_selected(&normalize_path(&event.paths[0])) {
+                        PENDING_CHANGES.push_event(&event);
+                    }
+                    continue;
+                }
+
+                let cloud = cloud.clone();
+                let keys = event.paths.iter().map(|x| normalize_path(x)).collect();
+
+                scheduler.spawn(keys, async move {
+                    let path = &event.paths[0];
+                    let normalized_path = normalize_path(path);
+
+                    if !is_selected(&normalized_path) {
+                        log::debug!("Ignore {:?} since it's not selected", path);
+                        return Ok(());
+                    }
+
+                    let is_file_exists = || async {
+                        let debug_statement = |x| {
+                            log::debug!("Is {:?} valid file path: {}", path, x);
+                            x
+                        };
+                        debug_statement(
+                            fs::metadata(path)
+                                .await
+                                .map(|m| m.is_file())
+                                .unwrap_or(false),
+                        )
+                    };
+
+                    match event.kind {
+                        EventKind::Create(_) if is_file_exists().await => {
+                            log::debug!("Uploading {:?}...", path);
+
+                            cloud
+                                .upload(&normalized_path, &fs::read(path).await?)
+                                .await
+                                .and_then(|_| {
+                                    if SYNCED_PATHS.inner.insert(normalized_path.clone()) {
+                                        SYNCED_PATHS.save()?;
+                                    }
+                                    SYNCED_STATES.record(&normalized_path, path);
+                                    SYNCED_STATES.save()?;
+                                    clear_failure(&normalized_path)
+                                })
+                                .or_else(|err| {
+                                    log_failure(&normalized_path, Change::Upload, err)
+                                })?;
+                        }
+                        EventKind::Remove(_) if SYNCED_PATHS.inner.contains(&normalized_path) => {
+                            log::debug!("Removing {:?}...", path);
+
+                            cloud
+                                .remove(&normalized_path)
+                                .await
+                                .and_then(|_| {
+                                    if SYNCED_PATHS.inner.remove(&normalized_path).is_some() {
+                                        SYNCED_PATHS.save()?;
+                                    }
+                                    if SYNCED_STATES.inner.remove(&normalized_path).is_some() {
+                                        SYNCED_STATES.save()?;
+                                    }
+                                    clear_failure(&normalized_path)
+                                })
+                                .or_else(|err| {
+                                    log_failure(&normalized_path, Change::Remove, err)
+                                })?;
+                        }
+                        EventKind::Modify(ModifyKind::Name(_)) if event.paths.len() == 2 => {
+                            log::debug!("Moving from {:?} to {:?}", path, event.paths[1]);
+
+                            if SYNCED_PATHS.inner.remove(&normalized_path).is_none() {
+                                cloud
+                                    .upload(
+                                        &normalize_path(&event.paths[1]),
+                                        &fs::read(&event.paths[1]).await?,
+                                    )
+                                    .await
+                                    .and_then(|_| {
+                                        SYNCED_PATHS.inner.insert(normalize_path(&event.paths[1]));
+                                        SYNCED_PATHS.save()?;
+                                        SYNCED_STATES.record(
+                                            &normalize_path(&event.paths[1]),
+                                            &event.paths[1],
+                                        );
+                                        SYNCED_STATES.save()?;
+                                        clear_failure(&normalize_path(&event.paths[1]))
+                                    })
+                                    .or_else(|err| {
+                                        log_failure(
+                                            &normalize_path(&event.paths[1]),
+                                            Change::Upload,
+                                            err,
+                                        )
+                                    })?;
+                            } else {
+                                cloud
+                                    .rename(&normalized_path, &normalize_path(&event.paths[1]))
+                                    .await
+                                    .and_then(|_| {
+                                        SYNCED_PATHS.inner.remove(&normalized_path);
+                                        SYNCED_PATHS.inner.insert(normalize_path(&event.paths[1]));
+                                        SYNCED_PATHS.save()?;
+                                        SYNCED_STATES.inner.remove(&normalized_path);
+                                        SYNCED_STATES.record(
+                                            &normalize_path(&event.paths[1]),
+                                            &event.paths[1],
+                                        );
+                                        SYNCED_STATES.save()?;
+                                        clear_failure(&normalized_path)?;
+                                        clear_failure(&normalize_path(&event.paths[1]))
+                                    })
+                                    .or_else(|err| {
+                                        DEAD_LETTERS.push(normalized_path.clone(), Change::Remove);
+                                        log_failure(
+                                            &normalize_path(&event.paths[1]),
+                                            Change::Upload,
+                                            err,
+                                        )
+                                    })?;
+                            }
+                        }
+                        _ => {}
+                    }
+
+                    Ok(())
+                });
+            }
+        }
+
+        for event in debouncer.drain() {
+            if is_selected(&normalize_path(&event.paths[0])) {
+                PENDING_CHANGES.push_event(&event);
+            }
+        }
+
+        scheduler.drain().await;
+
+        Result::<()>::Ok(())
+    });
+
+    let cloud = cloud_ref;
+    let cloud_task = spawn(async move {
+        while !shutdown::is_requested() {
+            check_connectivity().await;
+
+            if *PAUSED.lock().unwrap() {
+                log::debug!("Skip syncing.. it's paused");
+            } else if !schedule::is_open() {
+                log::debug!("Skip syncing.. it's outside of the sync schedule");
+            } else if *IS_INTERNET_AVAILABLE.lock().unwrap() {
+                *SYNCING.lock().unwrap() = true;
+
+                match sync_pass(cloud.as_ref()).await {
+                    Ok(report) => {
+                        log::debug!("{} file has synced", report.synced);
+
+                        if !report.skipped {
+                            notification::pass_finished(report.failed == 0);
+                        }
+                    }
+                    Err(err) => {
+                        notification::pass_finished(false);
+                        log_error(err)?
+                    }
+                }
+
+                *SYNCING.lock().unwrap() = false;
+            } else {
+                log::warn!("Skip syncing.. there are no internet connection");
+            }
+
+            tokio::select! {
+                _ = sleep(Duration::from_millis(CONFIG.interval)) => {}
+                _ = control::sync_requested() => log::info!("Sync requested"),
+                _ = shutdown::requested() => {}
+            }
+        }
+
+        Result::<()>::Ok(())
+    });
+
+    supervise(
+        vec![fs_task, cloud_task],
+        shutdown::wait_for_signal(),
+        shutdown::request,
+    )
+    .await?;
+
+    control_task.await.ok();
+    metrics_task.await.ok();
+    webhook::flush().await;
+
+    SYNCED_PATHS.save()?;
+    SYNCED_STATES.save()?;
+    PENDING_CHANGES.save()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn shuts_down_after_a_task_finished_early() {
+        let (stop, stopped) = oneshot::channel();
+        // The watcher returns at once in the download-only modes
+        let watcher = spawn(async { Ok(()) });
+        let syncer = spawn(async move {
+            stopped.await.ok();
+            Ok(())
+        });
+
+        sleep(Duration::from_millis(10)).await;
+
+        supervise(
+            vec![watcher, syncer],
+            sleep(Duration::from_millis(10)),
+            move || {
+                stop.send(()).ok();
+            },
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_on_a_failing_task() {
+        let failing = spawn(async { Err(anyhow!("watcher failed")) });
+        let running = spawn(async {
+            sleep(Duration::from_secs(60)).await;
+            Ok(())
+        });
+
+        let result = supervise(vec![failing, running], futures::future::pending(), || {}).await;
+
+        assert_eq!(result.unwrap_err().to_string(), "watcher failed");
+    }
+}
@@ -0,0 +1,241 @@
+use crate::control::{self, Dashboard, Request, Response};
+use crate::util::{activity::Action, format_rate, format_size};
+use anyhow::{bail, Result};
+use crossterm::{
+    event::{self, Event, KeyCode},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use std::{io, time::Duration};
+use time::OffsetDateTime;
+use tui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
+    widgets::{Block, Borders, Cell, Gauge, List, ListItem, Paragraph, Row, Table},
+    Frame, Terminal,
+};
+
+const REFRESH: Duration = Duration::from_secs(1);
+
+/// Shows the state of the running daemon until `q` or `Esc` is pressed
+pub async fn run() -> Result<()> {
+    // Fail early, before taking over the terminal
+    fetch().await?;
+
+    enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen)?;
+
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+    let result = draw_loop(&mut terminal).await;
+
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+
+    result
+}
+
+async fn fetch() -> Result<Dashboard> {
+    match control::send(Request::Dashboard).await? {
+        Response::Dashboard(dashboard) => Ok(*dashboard),
+        Response::Error { message } => bail!(message),
+        response => bail!("Unexpected response: {response:?}"),
+    }
+}
+
+async fn draw_loop<B: Backend>(terminal: &mut Terminal<B>) -> Result<()> {
+    loop {
+        let dashboard = fetch().await?;
+
+        terminal.draw(|f| draw(f, &dashboard))?;
+
+        if event::poll(REFRESH)? {
+            if let Event::Key(key) = event::read()? {
+                if matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn ago(timestamp: i64) -> String {
+    let seconds = (OffsetDateTime::now_utc().unix_timestamp() - timestamp).max(0);
+
+    match seconds {
+        0..=59 => format!("{seconds}s"),
+        60..=3599 => format!("{}m", seconds / 60),
+        _ => format!("{}h", seconds / 3600),
+    }
+}
+
+fn block(title: &str) -> Block<'_> {
+    Block::default().borders(Borders::ALL).title(title)
+}
+
+fn draw<B: Backend>(f: &mut Frame<B>, dashboard: &Dashboard) {
+    let status = &dashboard.status;
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3),
+            Constraint::Length(3),
+            Constraint::Percentage(35),
+            Constraint::Min(5),
+            Constraint::Length(3 + dashboard.stats.len() as u16),
+        ])
+        .split(f.size());
+
+    let color = match status.state() {
+        "idle" | "syncing" => Color::Green,
+        "offline" => Color::Red,
+        _ => Color::Yellow,
+    };
+    let header = Spans::from(vec![
+        Span::raw(format!("{}  ", dashboard.device)),
+        Span::styled(
+            status.state(),
+            Style::default().fg(color).add_modifier(Modifier::BOLD),
+        ),
+        Span::raw(format!(
+            "  synced {}  pending {}  failed {}  (q to quit)",
+            status.synced, status.pending, status.failed
+        )),
+    ]);
+    f.render_widget(Paragraph::new(header).block(block("rsink")), rows[0]);
+
+    let (done, total) = dashboard.pass.unwrap_or((0, 0));
+    let gauge = Gauge::default()
+        .block(block("Sync pass"))
+        .gauge_style(Style::default().fg(Color::Cyan))
+        .ratio(if total == 0 {
+            0.0
+        } else {
+            done as f64 / total as f64
+        })
+        .label(if total == 0 {
+            "idle".to_owned()
+        } else {
+            format!("{done}/{total} operation(s)")
+        });
+    f.render_widget(gauge, rows[1]);
+
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(rows[2]);
+
+    let active = block("Active");
+    let area = active.inner(columns[0]);
+    f.render_widget(active, columns[0]);
+
+    // One gauge per line, as many as fit
+    let lines = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(vec![Constraint::Length(1); area.height as usize])
+        .split(area);
+
+    for (transfer, line) in dashboard.active.iter().zip(lines) {
+        let (ratio, size) = if transfer.size == 0 {
+            (0.0, format!("{}/?", format_size(transfer.transferred)))
+        } else {
+            (
+                (transfer.transferred as f64 / transfer.size as f64).min(1.0),
+                format!(
+                    "{}/{}",
+                    format_size(transfer.transferred),
+                    format_size(transfer.size)
+                ),
+            )
+        };
+        let gauge = Gauge::default()
+            .gauge_style(Style::default().fg(Color::Cyan))
+            .ratio(ratio)
+            .label(format!(
+                "{:?} {} ({size}, {})",
+                transfer.change,
+                transfer.key,
+                ago(transfer.started_at)
+            ));
+        f.render_widget(gauge, line);
+    }
+
+    let queued = dashboard
+        .pending
+        .iter()
+        .map(|(key, change)| ListItem::new(format!("{change:?} {key}")))
+        .chain(dashboard.failed.iter().map(|(key, change)| {
+            ListItem::new(format!("{change:?} {key} (failed)"))
+                .style(Style::default().fg(Color::Red))
+        }))
+        .collect::<Vec<_>>();
+    f.render_widget(List::new(queued).block(block("Queued")), columns[1]);
+
+    let recent = dashboard
+        .recent
+        .iter()
+        .map(|entry| {
+            let line = format!("{:>4} {:?} {}", ago(entry.at), entry.action, entry.key);
+
+            match &entry.error {
+                Some(error) => {
+                    ListItem::new(format!("{line}: {error}")).style(Style::default().fg(Color::Red))
+                }
+                None if entry.action == Action::Conflict => {
+                    ListItem::new(line).style(Style::default().fg(Color::Yellow))
+                }
+                None => ListItem::new(line),
+            }
+        })
+        .collect::<Vec<_>>();
+    f.render_widget(List::new(recent).block(block("Recent")), rows[3]);
+
+    let stats = dashboard
+        .stats
+        .iter()
+        .map(|(backend, stats)| {
+            Row::new(vec![
+                Cell::from(backend.clone()),
+                Cell::from(format!(
+                    "{} ({})",
+                    stats.uploads,
+                    format_size(stats.uploaded_bytes)
+                )),
+                Cell::from(format!(
+                    "{} ({})",
+                    stats.downloads,
+                    format_size(stats.downloaded_bytes)
+                )),
+                Cell::from(stats.removals.to_string()),
+                Cell::from(stats.errors.to_string()),
+            ])
+        })
+        .collect::<Vec<_>>();
+    let limits = format!(
+        "Backends (limits: upload {}, download {})",
+        format_rate(status.max_upload_rate),
+        format_rate(status.max_download_rate)
+    );
+    let table = Table::new(stats)
+        .header(
+            Row::new(vec![
+                "Backend",
+                "Uploads",
+                "Downloads",
+                "Removals",
+                "Errors",
+            ])
+            .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .widths(&[
+            Constraint::Length(10),
+            Constraint::Length(20),
+            Constraint::Length(20),
+            Constraint::Length(10),
+            Constraint::Length(10),
+        ])
+        .block(block(&limits));
+    f.render_widget(table, rows[4]);
+}
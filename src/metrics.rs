@@ -0,0 +1,218 @@
+use crate::backends::{DOWNLOAD_LIMIT, UPLOAD_LIMIT};
+use crate::util::{activity::*, cache::last_synced_at, schedule, shutdown, Result};
+use crate::{DEAD_LETTERS, IS_INTERNET_AVAILABLE, PAUSED, PENDING_CHANGES, SYNCED_PATHS, SYNCING};
+use std::fmt::Write;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Serves the metrics in the Prometheus text format until a shutdown is requested
+pub async fn serve(address: &str) -> Result<()> {
+    let listener = TcpListener::bind(address).await?;
+
+    log::info!("Serving metrics on http://{address}/metrics");
+
+    loop {
+        let (stream, _) = tokio::select! {
+            result = listener.accept() => result?,
+            _ = shutdown::requested() => break,
+        };
+
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream).await {
+                log::debug!("Metrics request failed: {err:?}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn respond(mut stream: TcpStream) -> Result<()> {
+    let mut buffer = [0; 1024];
+    let read = stream.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..read]);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+    let (status, body) = if request.starts_with("GET ") && path == "/metrics" {
+        ("200 OK", render())
+    } else {
+        ("404 Not Found", "Not Found\n".to_owned())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+
+    Ok(())
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    writeln!(out, "# HELP {name} {help}").ok();
+    writeln!(out, "# TYPE {name} {kind}").ok();
+
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            writeln!(out, "{name} {value}").ok();
+        } else {
+            writeln!(out, "{name}{{{labels}}} {value}").ok();
+        }
+    }
+}
+
+fn gauge(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+fn render() -> String {
+    let mut out = String::new();
+    let stats = stats();
+    let per_backend = |value: fn(&Stats) -> u64| {
+        stats
+            .iter()
+            .map(|(backend, stats)| (format!("backend=\"{backend}\""), value(stats) as f64))
+            .collect::<Vec<_>>()
+    };
+
+    metric(
+        &mut out,
+        "rsink_uploaded_bytes_total",
+        "counter",
+        "Bytes uploaded since the daemon started",
+        &per_backend(|x| x.uploaded_bytes),
+    );
+    metric(
+        &mut out,
+        "rsink_downloaded_bytes_total",
+        "counter",
+        "Bytes downloaded since the daemon started",
+        &per_backend(|x| x.downloaded_bytes),
+    );
+    metric(
+        &mut out,
+        "rsink_operations_total",
+        "counter",
+        "Operations applied to the cloud by kind and result",
+        &operations()
+            .into_iter()
+            .map(|((backend, action, ok), count)| {
+                let action = format!("{action:?}").to_lowercase();
+                let result = if ok { "success" } else { "failure" };
+                (
+                    format!("backend=\"{backend}\",op=\"{action}\",result=\"{result}\""),
+                    count as f64,
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        &mut out,
+        "rsink_sync_passes_total",
+        "counter",
+        "Sync passes by result",
+        &passes()
+            .into_iter()
+            .map(|(result, count)| (format!("result=\"{result}\""), count as f64))
+            .collect::<Vec<_>>(),
+    );
+
+    if let Some(duration) = last_pass_duration() {
+        metric(
+            &mut out,
+            "rsink_sync_pass_duration_seconds",
+            "gauge",
+            "Duration of the last sync pass",
+            &[(String::new(), duration.as_secs_f64())],
+        );
+    }
+
+    if let Some(timestamp) = last_synced_at() {
+        metric(
+            &mut out,
+            "rsink_last_sync_timestamp_seconds",
+            "gauge",
+            "Start of the last successful sync pass",
+            &[(String::new(), timestamp as f64)],
+        );
+    }
+
+    metric(
+        &mut out,
+        "rsink_queue_depth",
+        "gauge",
+        "Changes waiting to be synced",
+        &[
+            (
+                "queue=\"pending\"".to_owned(),
+                PENDING_CHANGES.inner.len() as f64,
+            ),
+            (
+                "queue=\"failed\"".to_owned(),
+                DEAD_LETTERS.inner.len() as f64,
+            ),
+        ],
+    );
+    metric(
+        &mut out,
+        "rsink_active_transfers",
+        "gauge",
+        "Transfers in progress",
+        &[(String::new(), active().len() as f64)],
+    );
+    metric(
+        &mut out,
+        "rsink_synced_files",
+        "gauge",
+        "Files known to be in sync",
+        &[(String::new(), SYNCED_PATHS.inner.len() as f64)],
+    );
+    metric(
+        &mut out,
+        "rsink_online",
+        "gauge",
+        "Whether the internet is reachable",
+        &[(String::new(), gauge(*IS_INTERNET_AVAILABLE.lock().unwrap()))],
+    );
+    metric(
+        &mut out,
+        "rsink_syncing",
+        "gauge",
+        "Whether a sync pass is running",
+        &[(String::new(), gauge(*SYNCING.lock().unwrap()))],
+    );
+    metric(
+        &mut out,
+        "rsink_paused",
+        "gauge",
+        "Whether syncing is paused or outside of the schedule",
+        &[(
+            String::new(),
+            gauge(*PAUSED.lock().unwrap() || !schedule::is_open()),
+        )],
+    );
+    metric(
+        &mut out,
+        "rsink_rate_limit_bytes",
+        "gauge",
+        "Transfer rate limits in bytes per second, 0 means unlimited",
+        &[
+            (
+                "direction=\"upload\"".to_owned(),
+                UPLOAD_LIMIT.rate() as f64,
+            ),
+            (
+                "direction=\"download\"".to_owned(),
+                DOWNLOAD_LIMIT.rate() as f64,
+            ),
+        ],
+    );
+
+    out
+}
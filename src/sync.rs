@@ -0,0 +1,581 @@
+use crate::backends::*;
+use crate::util::{
+    audit::AuditEntry,
+    cache::set_last_synced_at,
+    config::{WebhookEvent, CONFIG},
+    device::*,
+    hooks::Hook,
+    queue::{Change, Replayed},
+    *,
+};
+use crate::{EXPECTED_CHANGES, PENDING_CHANGES, SYNCED_PATHS, SYNCED_STATES};
+use serde_json::json;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::fs;
+
+/// Outcome of a sync pass
+#[derive(Default, Debug)]
+pub struct PassReport {
+    pub synced: usize,
+    /// Changes that couldn't be synced, either still pending or moved to the dead letters
+    pub failed: usize,
+    pub conflicts: usize,
+    /// Removals refused by the delete threshold
+    pub refused: usize,
+    /// Transfers waiting for their sync window
+    pub deferred: usize,
+    /// Another device holds the bucket lease
+    pub skipped: bool,
+    pub interrupted: bool,
+}
+
+impl PassReport {
+    /// One of success, failure or skipped
+    pub fn result(&self) -> &'static str {
+        if self.skipped {
+            "skipped"
+        } else if self.failed == 0 && !self.interrupted {
+            "success"
+        } else {
+            "failure"
+        }
+    }
+}
+
+/// Operations a sync pass is going to apply
+#[derive(Default)]
+pub struct Plan {
+    pub operations: Vec<Operation>,
+    /// Removals held back by the delete threshold
+    pub refused: Vec<Operation>,
+}
+
+async fn apply<B: Backend + Sync>(cloud: &B, op: &Operation) -> Result<()> {
+    match op {
+        Operation::Checked(path) => {
+            SYNCED_PATHS.inner.insert(normalize_path(path));
+
+            if !SYNCED_STATES.inner.contains_key(&normalize_path(path)) {
+                SYNCED_STATES.record(&normalize_path(path), path);
+            }
+        }
+        Operation::Upload(path) => {
+            log::debug!("Saving {path:?}");
+            cloud
+                .upload(&normalize_path(path), &fs::read(path).await?)
+                .await?;
+            SYNCED_PATHS.inner.insert(normalize_path(path));
+            SYNCED_STATES.record(&normalize_path(path), path);
+        }
+        Operation::Write(path, size) => {
+            activity::expect_size(&normalize_path(path), *size);
+            let buffer = cloud.download(&normalize_path(path)).await?;
+            log::debug!("Writing {} bytes to {path:?}", buffer.len());
+            create_parent_dir(path).await;
+            EXPECTED_CHANGES.insert(path.clone(), Some(content_hash(&buffer)));
+            write_atomically(path, &buffer).await?;
+            SYNCED_STATES.record(&normalize_path(path), path);
+            hooks::downloaded(path, &normalize_path(path), buffer.len()).await;
+        }
+        Operation::WriteEmpty(path) => {
+            log::debug!("Writing empty buffer to {path:?}");
+            create_parent_dir(path).await;
+            EXPECTED_CHANGES.insert(path.clone(), Some(content_hash(&[])));
+            write_atomically(path, &[]).await?;
+            SYNCED_STATES.record(&normalize_path(path), path);
+            hooks::downloaded(path, &normalize_path(path), 0).await;
+        }
+        Operation::Remove(path) => {
+            log::debug!("Removing {path:?} from the cloud");
+            cloud.remove(&normalize_path(path)).await?;
+            SYNCED_PATHS.inner.remove(&normalize_path(path));
+            SYNCED_STATES.inner.remove(&normalize_path(path));
+        }
+        Operation::RemoveLocal(path) => {
+            let key = normalize_path(path);
+            let entry =
+                AuditEntry::new(activity::Action::RemoveLocal, &key).note("deleted from the cloud");
+
+            log::info!("Removing {path:?} since it was deleted from the cloud");
+            SYNCED_PATHS.inner.remove(&key);
+            SYNCED_STATES.inner.remove(&key);
+            EXPECTED_CHANGES.insert(path.clone(), None);
+
+            if path.is_dir() {
+                fs::remove_dir(&path).await?;
+                entry.save();
+            } else if path.is_file() {
+                let buffer = fs::read(&path).await?;
+                fs::remove_file(&path).await?;
+                entry.content(&buffer).save();
+            }
+
+            activity::record(&key, activity::Action::RemoveLocal, None);
+        }
+        Operation::Deleted(_) | Operation::Conflict(_) => {}
+    }
+
+    Ok(())
+}
+
+async fn create_parent_dir(path: &Path) {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await.ok();
+    }
+}
+
+/// Runs a full sync pass between the cloud and the local directory, between the sync hooks
+///
+/// The pass is skipped while another device holds the bucket lease
+pub async fn sync_pass<B: Backend + Sync>(cloud: &B) -> Result<PassReport> {
+    let _pass = audit::PassGuard::begin();
+
+    hooks::run(Hook::PreSync, &[]).await?;
+
+    let result = sync_with_lease(cloud).await;
+    let vars = match &result {
+        Ok(report) => vec![
+            ("RESULT", report.result().to_owned()),
+            ("SYNCED", report.synced.to_string()),
+            ("FAILED", report.failed.to_string()),
+            ("CONFLICTS", report.conflicts.to_string()),
+        ],
+        Err(err) => vec![
+            ("RESULT", "error".to_owned()),
+            ("ERROR", format!("{err:#}")),
+        ],
+    };
+
+    hooks::run(Hook::PostSync, &vars)
+        .await
+        .unwrap_or_else(|err| log::warn!("{err:#}"));
+
+    let mut summary = json!({
+        "at": OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+        "device": *DEVICE_ID,
+        "device_name": *DEVICE_NAME,
+    });
+
+    for (key, value) in vars {
+        summary[key.to_lowercase()] = match value.parse::<u64>() {
+            Ok(count) => json!(count),
+            Err(_) => json!(value),
+        };
+    }
+
+    webhook::send(WebhookEvent::Pass, summary);
+
+    result
+}
+
+async fn sync_with_lease<B: Backend + Sync>(cloud: &B) -> Result<PassReport> {
+    EXPECTED_CHANGES.clear();
+
+    if let Some(lease) = cloud.acquire_lease(&DEVICE_ID, lease_ttl()).await? {
+        log::info!(
+            "Device {} is syncing until {}, Skipping this pass",
+            lease.device,
+            lease.expires_at
+        );
+        activity::record_pass(Duration::ZERO, "skipped");
+        return Ok(PassReport {
+            failed: PENDING_CHANGES.inner.len(),
+            skipped: true,
+            ..Default::default()
+        });
+    }
+
+    let started_at = Instant::now();
+    let result = sync_leased(cloud).await;
+    activity::set_pass_progress(0, 0);
+    activity::record_pass(
+        started_at.elapsed(),
+        result.as_ref().map_or("failure", PassReport::result),
+    );
+
+    cloud.release_lease(&DEVICE_ID).await.or_else(log_error)?;
+
+    result
+}
+
+/// Runs the pass while holding the lease, Replaying the offline changes first
+/// and the ones made meanwhile at the end
+///
+/// The lease is renewed in the background for as long as the pass runs
+async fn sync_leased<B: Backend + Sync>(cloud: &B) -> Result<PassReport> {
+    let lost = AtomicBool::new(false);
+    let pass = run_leased(cloud, &lost);
+    tokio::pin!(pass);
+
+    tokio::select! {
+        result = &mut pass => result,
+        _ = keep_lease(cloud, &lost) => pass.await,
+    }
+}
+
+async fn run_leased<B: Backend + Sync>(cloud: &B, lost: &AtomicBool) -> Result<PassReport> {
+    let started_at = OffsetDateTime::now_utc().unix_timestamp();
+
+    replay_pending(cloud, "offline").await?;
+
+    cloud
+        .register_device(current_device())
+        .await
+        .or_else(log_error)?;
+
+    let report = run_pass(cloud, lost).await?;
+
+    let replayed = replay_pending(cloud, "made while syncing").await?;
+
+    // Changes still queued failed, unless they wait for their sync window
+    let pending = PENDING_CHANGES
+        .inner
+        .len()
+        .saturating_sub(replayed.deferred);
+    let report = PassReport {
+        failed: report.failed + pending,
+        deferred: report.deferred + replayed.deferred,
+        ..report
+    };
+
+    if report.result() == "success" {
+        set_last_synced_at(started_at)?;
+    }
+
+    Ok(report)
+}
+
+/// Renews the lease every half TTL, Returns once another device took it over
+async fn keep_lease<B: Backend + Sync>(cloud: &B, lost: &AtomicBool) {
+    let mut interval = tokio::time::interval(lease_ttl() / 2);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        match cloud.acquire_lease(&DEVICE_ID, lease_ttl()).await {
+            Ok(Some(lease)) => {
+                log::warn!("Lost the lease to device {}, Stopping", lease.device);
+                lost.store(true, Ordering::Relaxed);
+                return;
+            }
+            Ok(None) => {}
+            Err(err) => log::warn!("Couldn't renew the lease: {err:#}"),
+        }
+    }
+}
+
+async fn replay_pending<B: Backend + Sync>(cloud: &B, kind: &str) -> Result<Replayed> {
+    if PENDING_CHANGES.inner.is_empty() {
+        return Ok(Replayed::default());
+    }
+
+    let replayed = PENDING_CHANGES.replay(cloud).await?;
+    log::info!("Replayed {} {kind} change(s)", replayed.replayed);
+
+    if replayed.deferred > 0 {
+        log::info!(
+            "{} {kind} change(s) wait for their sync window",
+            replayed.deferred
+        );
+    }
+
+    Ok(replayed)
+}
+
+/// Name of the device that last wrote the cloud version of `key`, Falling back to its ID
+async fn cloud_writer<B: Backend + Sync>(cloud: &B, key: &str) -> Option<String> {
+    let id = match cloud.last_writer(key).await {
+        Ok(id) => id?,
+        Err(err) => {
+            log::warn!("Couldn't get the last writer of {key}: {err:#}");
+            return None;
+        }
+    };
+    let devices = cloud.devices().await.unwrap_or_default();
+
+    Some(
+        devices
+            .into_iter()
+            .find(|device| device.id == id)
+            .map_or(id, |device| device.name),
+    )
+}
+
+fn lease_ttl() -> Duration {
+    Duration::from_secs(CONFIG.lease_ttl)
+}
+
+/// Computes the operations of a sync pass, Including the local-only uploads and removals
+///
+/// A dry run doesn't touch the cloud nor the local directory
+pub async fn plan<B: Backend + Sync>(cloud: &B, dry_run: bool) -> Result<Plan> {
+    let mut plan = Plan::default();
+    let operations = cloud.sync(dry_run).await?;
+    let objects = operations
+        .iter()
+        .filter(|x| !matches!(x, Operation::Deleted(_)))
+        .map(|x| x.path())
+        .collect::<DashSet<PathBuf>>();
+    let tombstones = operations
+        .iter()
+        .filter(|x| matches!(x, Operation::Deleted(_)))
+        .map(|x| x.path())
+        .collect::<DashSet<PathBuf>>();
+
+    for op in operations {
+        match op.with_mode(CONFIG.mode) {
+            Some(op @ Operation::Remove(_)) => plan.refused.push(op),
+            Some(op) => plan.operations.push(op),
+            None => {}
+        }
+    }
+
+    if exceeds_delete_threshold(plan.refused.len(), objects.len()) {
+        log::error!(
+            "Refusing to remove {} of {} cloud files in one pass",
+            plan.refused.len(),
+            objects.len()
+        );
+    } else {
+        plan.operations.append(&mut plan.refused);
+    }
+
+    let files = walk_dir(&CONFIG.path)?;
+    let mut removals = vec![];
+
+    for entry in &files {
+        let path = entry.path();
+        let normalized_path = normalize_path(&path);
+
+        if !is_selected(&normalized_path) || objects.contains(&path) {
+            continue;
+        }
+
+        // Only deliberate deletions (with a tombstone) are propagated,
+        // a partial listing must never wipe local files
+        let was_deleted =
+            SYNCED_PATHS.inner.contains(&normalized_path) && tombstones.contains(&path);
+
+        if CONFIG.mode.should_remove_local(was_deleted) {
+            removals.push(Operation::RemoveLocal(path));
+        } else if CONFIG.mode.can_upload() {
+            log::debug!("{:?} is missing in the cloud", path);
+            plan.operations.push(Operation::Upload(path));
+        } else {
+            log::debug!("{:?} not synced, Skipping in {:?} mode", path, CONFIG.mode);
+        }
+    }
+
+    if exceeds_delete_threshold(removals.len(), files.len()) {
+        log::error!(
+            "Refusing to remove {} of {} local files in one pass",
+            removals.len(),
+            files.len()
+        );
+        plan.refused.append(&mut removals);
+    } else {
+        plan.operations.append(&mut removals);
+    }
+
+    Ok(plan)
+}
+
+async fn run_pass<B: Backend + Sync>(cloud: &B, lost: &AtomicBool) -> Result<PassReport> {
+    let plan = plan(cloud, false).await?;
+    let mut report = PassReport {
+        refused: plan.refused.len(),
+        ..Default::default()
+    };
+
+    log::debug!("Sync operations: {}", plan.operations.len());
+
+    for (done, op) in plan.operations.iter().enumerate() {
+        activity::set_pass_progress(done, plan.operations.len());
+
+        if shutdown::is_requested() || lost.load(Ordering::Relaxed) {
+            report.interrupted = true;
+            break;
+        }
+
+        if let Operation::Conflict(path) = op {
+            log::warn!("{path:?} was changed locally and in the cloud, Keeping the newer version");
+            let key = normalize_path(path);
+            let kept = plan.operations.iter().find_map(|x| match x {
+                Operation::Upload(p) if p == path => Some("kept the local version"),
+                Operation::Write(p, _) if p == path => Some("kept the cloud version"),
+                _ => None,
+            });
+
+            let writer = cloud_writer(cloud, &key).await;
+            let mut note = kept.unwrap_or("kept both versions").to_owned();
+
+            if let Some(writer) = &writer {
+                note += &format!(", the cloud version was written by {writer}");
+            }
+
+            report.conflicts += 1;
+            activity::record(&key, activity::Action::Conflict, None);
+            notification::conflict(&key, writer.as_deref());
+            AuditEntry::new(activity::Action::Conflict, &key)
+                .note(&note)
+                .save();
+            continue;
+        }
+
+        if Change::of(op).is_some() && !schedule::allows_operation(op) {
+            log::debug!("Deferring {:?} until its sync window", op.path());
+            report.deferred += 1;
+            continue;
+        }
+
+        match apply(cloud, op).await {
+            Ok(_) if Change::of(op).is_some() => {
+                report.synced += 1;
+                clear_failure(&normalize_path(&op.path()))?;
+            }
+            Ok(_) if matches!(op, Operation::Checked(_)) => {
+                clear_failure(&normalize_path(&op.path()))?;
+            }
+            Ok(_) => {}
+            Err(err) => match Change::of(op) {
+                Some(change) => {
+                    report.failed += 1;
+                    log_failure(&normalize_path(&op.path()), change, err)?
+                }
+                None => log_error(err)?,
+            },
+        }
+    }
+
+    SYNCED_PATHS.save()?;
+    SYNCED_STATES.save()?;
+
+    if report.interrupted {
+        log::info!("Sync pass was interrupted, Saving progress...");
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::{self, FakeCloud};
+    use std::fs;
+
+    fn create(key: &str, synced: bool) {
+        fs::write(key_to_path(key), key).unwrap();
+
+        if synced {
+            SYNCED_PATHS.inner.insert(key.to_owned());
+        }
+    }
+
+    fn keys(operations: &[Operation], kind: fn(&Operation) -> bool) -> Vec<String> {
+        let mut keys = operations
+            .iter()
+            .filter(|op| kind(op))
+            .map(|op| normalize_path(&op.path()))
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[tokio::test]
+    async fn only_deliberate_deletions_remove_local_files() {
+        let _sync_dir = testing::sync_dir().await;
+
+        create("kept", true);
+        create("deleted", true);
+        create("missing", true);
+        create("recreated", false);
+
+        let cloud = FakeCloud {
+            objects: vec!["kept"],
+            tombstones: vec!["deleted", "recreated"],
+            ..Default::default()
+        };
+        let planned = plan(&cloud, true).await.unwrap();
+
+        assert_eq!(
+            keys(&planned.operations, |op| matches!(
+                op,
+                Operation::RemoveLocal(_)
+            )),
+            ["deleted"]
+        );
+        // A file missing from a partial listing, or created after the deletion, is uploaded again
+        assert_eq!(
+            keys(&planned.operations, |op| matches!(op, Operation::Upload(_))),
+            ["missing", "recreated"]
+        );
+        assert!(planned.refused.is_empty());
+
+        for key in ["a", "b", "c", "d", "e", "f"] {
+            create(key, true);
+        }
+
+        let cloud = FakeCloud {
+            objects: vec!["kept"],
+            tombstones: vec!["a", "b", "c", "d", "e", "f"],
+            ..Default::default()
+        };
+        let planned = plan(&cloud, true).await.unwrap();
+
+        // 6 of 10 local files is above the threshold
+        assert!(!planned
+            .operations
+            .iter()
+            .any(|op| matches!(op, Operation::RemoveLocal(_))));
+        assert_eq!(
+            keys(&planned.refused, |op| matches!(
+                op,
+                Operation::RemoveLocal(_)
+            )),
+            ["a", "b", "c", "d", "e", "f"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_lease_is_renewed_until_another_device_takes_it() {
+        testing::init();
+
+        let cloud = FakeCloud::default();
+        let lost = AtomicBool::new(false);
+        let renewed = tokio::select! {
+            _ = keep_lease(&cloud, &lost) => false,
+            _ = tokio::time::sleep(lease_ttl() * 3) => true,
+        };
+
+        assert!(renewed);
+        assert!(!lost.load(Ordering::Relaxed));
+
+        let cloud = FakeCloud {
+            lease_holder: Some("other"),
+            ..Default::default()
+        };
+        let started_at = tokio::time::Instant::now();
+        keep_lease(&cloud, &lost).await;
+
+        assert_eq!(started_at.elapsed(), lease_ttl() / 2);
+        assert!(lost.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn a_pass_stops_once_the_lease_is_lost() {
+        let _sync_dir = testing::sync_dir().await;
+
+        create("local", false);
+
+        let cloud = FakeCloud::default();
+        let report = run_pass(&cloud, &AtomicBool::new(true)).await.unwrap();
+
+        assert!(report.interrupted);
+        assert!(cloud.calls().is_empty());
+    }
+}
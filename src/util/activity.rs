@@ -0,0 +1,216 @@
+use super::queue::Change;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use time::OffsetDateTime;
+
+/// How many recent operations are kept in memory
+const MAX_RECENT: usize = 100;
+
+lazy_static! {
+    static ref NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    /// The running transfers and their bytes transferred so far
+    static ref ACTIVE: DashMap<u64, (Transfer, Arc<AtomicU64>)> = DashMap::new();
+    /// Sizes of the downloads about to start, as listed by the sync
+    static ref EXPECTED_SIZES: DashMap<String, u64> = DashMap::new();
+    static ref RECENT: Mutex<VecDeque<Entry>> = Mutex::new(VecDeque::new());
+    static ref STATS: DashMap<String, Stats> = DashMap::new();
+    static ref PASS_DONE: AtomicUsize = AtomicUsize::new(0);
+    static ref PASS_TOTAL: AtomicUsize = AtomicUsize::new(0);
+    static ref OPERATIONS: DashMap<(String, Action, bool), u64> = DashMap::new();
+    static ref PASSES: DashMap<&'static str, u64> = DashMap::new();
+    static ref LAST_PASS_DURATION: Mutex<Option<Duration>> = Mutex::new(None);
+}
+
+/// A transfer that is still running
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Transfer {
+    pub key: String,
+    pub change: Change,
+    /// 0 while unknown, e.g. for a download replayed without listing the cloud
+    pub size: u64,
+    /// Bytes transferred so far
+    #[serde(default)]
+    pub transferred: u64,
+    pub started_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Upload,
+    Download,
+    Remove,
+    /// A local file removed since it was deleted from the cloud
+    RemoveLocal,
+    Rename,
+    Conflict,
+}
+
+/// A finished operation, `error` is set when it failed
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+    pub at: i64,
+    pub key: String,
+    pub action: Action,
+    pub error: Option<String>,
+}
+
+/// Counters of a backend since the daemon started
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Stats {
+    pub uploads: u64,
+    pub downloads: u64,
+    pub removals: u64,
+    pub uploaded_bytes: u64,
+    pub downloaded_bytes: u64,
+    pub errors: u64,
+}
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+/// Tracks a transfer until the returned guard is dropped
+pub fn start(key: &str, change: Change, size: u64) -> TransferGuard {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    ACTIVE.insert(
+        id,
+        (
+            Transfer {
+                key: key.to_owned(),
+                change,
+                size,
+                transferred: 0,
+                started_at: now(),
+            },
+            Arc::default(),
+        ),
+    );
+
+    TransferGuard { id }
+}
+
+pub struct TransferGuard {
+    id: u64,
+}
+
+impl Drop for TransferGuard {
+    fn drop(&mut self) {
+        ACTIVE.remove(&self.id);
+    }
+}
+
+/// Remembers the size of `key` for its next download
+pub fn expect_size(key: &str, size: u64) {
+    EXPECTED_SIZES.insert(key.to_owned(), size);
+}
+
+/// The size of the download of `key` about to start, 0 if unknown
+pub fn expected_size(key: &str) -> u64 {
+    EXPECTED_SIZES.remove(key).map_or(0, |(_, size)| size)
+}
+
+/// Counter of the bytes transferred by the running `change` of `key`, if any
+pub fn progress(key: &str, change: Change) -> Option<Arc<AtomicU64>> {
+    ACTIVE
+        .iter()
+        .filter(|x| x.0.key == key && x.0.change == change)
+        .max_by_key(|x| *x.key())
+        .map(|x| x.1.clone())
+}
+
+pub fn record(key: &str, action: Action, error: Option<String>) {
+    let mut recent = RECENT.lock().unwrap();
+
+    if recent.len() == MAX_RECENT {
+        recent.pop_front();
+    }
+
+    recent.push_back(Entry {
+        at: now(),
+        key: key.to_owned(),
+        action,
+        error,
+    });
+}
+
+/// Updates the counters of the given backend
+pub fn count(backend: &str, update: impl FnOnce(&mut Stats)) {
+    update(&mut STATS.entry(backend.to_owned()).or_default());
+}
+
+/// Counts a finished operation of the given backend
+pub fn count_operation(backend: &str, action: Action, ok: bool) {
+    *OPERATIONS
+        .entry((backend.to_owned(), action, ok))
+        .or_default() += 1;
+}
+
+/// Operation counts by backend, action and whether they succeeded
+pub fn operations() -> BTreeMap<(String, Action, bool), u64> {
+    OPERATIONS
+        .iter()
+        .map(|x| (x.key().clone(), *x.value()))
+        .collect()
+}
+
+/// Records a finished sync pass, `result` is one of success, failure or skipped
+pub fn record_pass(duration: Duration, result: &'static str) {
+    *PASSES.entry(result).or_default() += 1;
+
+    if result != "skipped" {
+        *LAST_PASS_DURATION.lock().unwrap() = Some(duration);
+    }
+}
+
+pub fn passes() -> BTreeMap<&'static str, u64> {
+    PASSES.iter().map(|x| (*x.key(), *x.value())).collect()
+}
+
+pub fn last_pass_duration() -> Option<Duration> {
+    *LAST_PASS_DURATION.lock().unwrap()
+}
+
+pub fn set_pass_progress(done: usize, total: usize) {
+    PASS_DONE.store(done, Ordering::Relaxed);
+    PASS_TOTAL.store(total, Ordering::Relaxed);
+}
+
+/// Operations done and planned by the running sync pass, if any
+pub fn pass_progress() -> Option<(usize, usize)> {
+    let total = PASS_TOTAL.load(Ordering::Relaxed);
+    (total > 0).then_some((PASS_DONE.load(Ordering::Relaxed), total))
+}
+
+pub fn active() -> Vec<Transfer> {
+    let mut active = ACTIVE
+        .iter()
+        .map(|x| Transfer {
+            transferred: x.1.load(Ordering::Relaxed),
+            ..x.0.clone()
+        })
+        .collect::<Vec<_>>();
+    active.sort_by_key(|x| x.started_at);
+    active
+}
+
+/// The most recent operations first
+pub fn recent() -> Vec<Entry> {
+    RECENT.lock().unwrap().iter().rev().cloned().collect()
+}
+
+pub fn stats() -> BTreeMap<String, Stats> {
+    STATS
+        .iter()
+        .map(|x| (x.key().clone(), x.value().clone()))
+        .collect()
+}
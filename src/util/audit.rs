@@ -0,0 +1,164 @@
+use super::{activity::Action, config::WebhookEvent, device::*, webhook};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+lazy_static! {
+    static ref CURRENT_PASS: Mutex<Option<String>> = Mutex::new(None);
+    static ref FILE: Mutex<()> = Mutex::new(());
+}
+
+/// An applied operation, one JSON object per line of the audit log
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditEntry {
+    pub at: String,
+    pub device: String,
+    pub device_name: String,
+    pub action: Action,
+    pub path: String,
+    /// The old path of a rename
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Hex SHA-256 of the content
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// `pass <started at>` or `watcher` for local changes
+    pub origin: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(action: Action, path: &str) -> Self {
+        Self {
+            at: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            device: DEVICE_ID.clone(),
+            device_name: DEVICE_NAME.clone(),
+            action,
+            path: path.to_owned(),
+            from: None,
+            size: None,
+            hash: None,
+            origin: CURRENT_PASS
+                .lock()
+                .unwrap()
+                .clone()
+                .unwrap_or_else(|| "watcher".to_owned()),
+            note: None,
+        }
+    }
+
+    pub fn content(self, buffer: &[u8]) -> Self {
+        Self {
+            size: Some(buffer.len() as u64),
+            hash: Some(hex::encode(Sha256::digest(buffer))),
+            ..self
+        }
+    }
+
+    pub fn from(self, from: &str) -> Self {
+        Self {
+            from: Some(from.to_owned()),
+            ..self
+        }
+    }
+
+    pub fn note(self, note: &str) -> Self {
+        Self {
+            note: Some(note.to_owned()),
+            ..self
+        }
+    }
+
+    /// Appends the entry to the audit log and sends it to the webhook, failures are only logged
+    pub fn save(self) {
+        let _lock = FILE.lock().unwrap();
+        let result = serde_json::to_string(&self)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| {
+                let mut file = File::options().create(true).append(true).open(path())?;
+                writeln!(file, "{line}")?;
+                Ok(())
+            });
+
+        if let Err(err) = result {
+            log::error!("Couldn't write to the audit log: {err:?}");
+        }
+
+        if let Ok(entry) = serde_json::to_value(&self) {
+            webhook::send(WebhookEvent::File, entry);
+        }
+    }
+}
+
+pub fn path() -> PathBuf {
+    data_path("audit.log")
+}
+
+/// Operations applied while the guard is alive are attributed to the same sync pass
+pub struct PassGuard;
+
+impl PassGuard {
+    pub fn begin() -> Self {
+        let started_at = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        *CURRENT_PASS.lock().unwrap() = Some(format!("pass {started_at}"));
+        PassGuard
+    }
+}
+
+impl Drop for PassGuard {
+    fn drop(&mut self) {
+        *CURRENT_PASS.lock().unwrap() = None;
+    }
+}
+
+/// Reads the entries touching the given path (or anything under it), oldest first
+pub fn entries(filter: Option<&str>) -> Result<Vec<AuditEntry>> {
+    let file = match File::open(path()) {
+        Ok(file) => file,
+        Err(_) => return Ok(vec![]),
+    };
+    let matches = |key: &str| match filter {
+        Some(filter) => key == filter || key.starts_with(&format!("{filter}/")),
+        None => true,
+    };
+
+    Ok(BufReader::new(file)
+        .lines()
+        .map_while(|line| line.ok())
+        .filter_map(|line| serde_json::from_str::<AuditEntry>(&line).ok())
+        .filter(|entry| matches(&entry.path) || entry.from.as_deref().is_some_and(matches))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing;
+
+    #[test]
+    fn content_is_hashed_with_sha256() {
+        testing::init();
+
+        let entry = AuditEntry::new(Action::Upload, "file").content(b"abc");
+
+        assert_eq!(entry.size, Some(3));
+        assert_eq!(
+            entry.hash.as_deref(),
+            Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+    }
+}
@@ -1,26 +1,54 @@
 use anyhow::Result;
-use dashmap::DashSet;
-use std::{fs, io::Write, path::PathBuf};
+use dashmap::{DashMap, DashSet};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+use time::OffsetDateTime;
 
 pub struct Cache {
     path: PathBuf,
     pub inner: DashSet<String>,
 }
 
-impl Cache {
-    pub fn new(name: &str) -> Self {
-        let mut path = dirs::cache_dir().unwrap();
+pub fn cache_path(name: &str) -> PathBuf {
+    let mut path = dirs::cache_dir().unwrap();
 
-        path.push("rsink");
+    path.push("rsink");
 
-        fs::create_dir_all(&path).ok();
+    fs::create_dir_all(&path).ok();
 
-        path.push(name);
+    path.push(name);
+
+    path
+}
+
+/// Unix timestamp of the start of the last successful sync pass
+pub fn last_synced_at() -> Option<i64> {
+    fs::read_to_string(cache_path("last_synced_at"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+pub fn set_last_synced_at(timestamp: i64) -> Result<()> {
+    fs::write(cache_path("last_synced_at"), timestamp.to_string())?;
+    Ok(())
+}
+
+impl Cache {
+    pub fn new(name: &str) -> Self {
+        let path = cache_path(name);
 
         let mut file = fs::File::options()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&path)
             .unwrap();
 
@@ -31,7 +59,7 @@ impl Cache {
         let array: Vec<String> = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
 
         Self {
-            inner: DashSet::from_iter(array.into_iter()),
+            inner: DashSet::from_iter(array),
             path,
         }
     }
@@ -43,3 +71,132 @@ impl Cache {
         Ok(())
     }
 }
+
+/// A file as it was when last synced, Conflicts are detected against it
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SyncedState {
+    pub size: u64,
+    /// Unix timestamp of the local modification
+    pub modified: i64,
+    /// Unix timestamp of the sync
+    pub synced_at: i64,
+    /// Unix timestamp of the cloud object as last listed in sync, Unknown until the next listing
+    #[serde(default)]
+    pub cloud_modified: Option<i64>,
+}
+
+impl SyncedState {
+    /// Whether both the local file and the cloud object changed since this state
+    pub fn conflicts(
+        &self,
+        local_size: u64,
+        local_modified: i64,
+        cloud_size: u64,
+        cloud_modified: i64,
+    ) -> bool {
+        let local_changed = local_size != self.size || local_modified != self.modified;
+        let cloud_changed = cloud_size != self.size
+            || self
+                .cloud_modified
+                .is_some_and(|modified| modified != cloud_modified);
+
+        local_changed && cloud_changed
+    }
+}
+
+pub struct StateCache {
+    path: PathBuf,
+    pub inner: DashMap<String, SyncedState>,
+}
+
+impl StateCache {
+    pub fn new(name: &str) -> Self {
+        let path = cache_path(name);
+        let map: BTreeMap<String, SyncedState> = fs::read(&path)
+            .ok()
+            .and_then(|buffer| serde_json::from_slice(&buffer).ok())
+            .unwrap_or_default();
+
+        Self {
+            inner: DashMap::from_iter(map),
+            path,
+        }
+    }
+
+    /// Remembers the local file of `key` as synced now
+    pub fn record(&self, key: &str, path: &Path) {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return,
+        };
+        let modified = metadata
+            .modified()
+            .map(|x| OffsetDateTime::from(x).unix_timestamp())
+            .unwrap_or_default();
+
+        self.inner.insert(
+            key.to_owned(),
+            SyncedState {
+                size: metadata.len(),
+                modified,
+                synced_at: OffsetDateTime::now_utc().unix_timestamp(),
+                cloud_modified: None,
+            },
+        );
+    }
+
+    /// Remembers the cloud object of a synced `key` as last modified at `cloud_modified`
+    pub fn set_cloud_modified(&self, key: &str, cloud_modified: i64) {
+        if let Some(mut state) = self.inner.get_mut(key) {
+            state.cloud_modified = Some(cloud_modified);
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let map: BTreeMap<String, SyncedState> =
+            self.inner.iter().map(|x| (x.key().clone(), *x)).collect();
+        fs::write(&self.path, serde_json::to_string(&map)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATE: SyncedState = SyncedState {
+        size: 10,
+        modified: 100,
+        synced_at: 200,
+        cloud_modified: Some(150),
+    };
+
+    #[test]
+    fn changes_on_one_side_are_not_conflicts() {
+        assert!(!STATE.conflicts(10, 100, 20, 300));
+        assert!(!STATE.conflicts(10, 100, 10, 300));
+        assert!(!STATE.conflicts(20, 300, 10, 150));
+    }
+
+    #[test]
+    fn changes_on_both_sides_are_conflicts() {
+        assert!(STATE.conflicts(20, 300, 30, 300));
+        assert!(STATE.conflicts(10, 300, 10, 300));
+    }
+
+    #[test]
+    fn cloud_times_are_only_compared_with_cloud_times() {
+        // A cloud clock behind the local one still shows the change
+        assert!(STATE.conflicts(10, 300, 10, 120));
+        // Synced long after the upload, the cloud time is older than the sync but unchanged
+        assert!(!STATE.conflicts(20, 300, 10, 150));
+
+        let unknown = SyncedState {
+            cloud_modified: None,
+            ..STATE
+        };
+
+        assert!(!unknown.conflicts(20, 300, 10, 300));
+        assert!(unknown.conflicts(20, 300, 30, 300));
+    }
+}
@@ -1,16 +1,22 @@
 use crate::config::CONFIG;
-use crate::IS_INTERNET_AVAILABLE;
+use crate::util::{notification, queue::Change};
+use crate::{DEAD_LETTERS, EXPECTED_CHANGES, IS_INTERNET_AVAILABLE};
 pub use anyhow::Result;
+use notify::Event;
 use std::{
+    collections::hash_map::DefaultHasher,
     fs::{self, DirEntry},
+    hash::{Hash, Hasher},
     path::{Path, PathBuf},
+    sync::Mutex,
 };
 use time::OffsetDateTime;
+use tokio::io::AsyncWriteExt;
 
 pub fn normalize_path(path: &Path) -> String {
     let mut normalized_path = PathBuf::new();
     let mut found = false;
-    let root = CONFIG.path.components().last().unwrap();
+    let root = CONFIG.path.components().next_back().unwrap();
 
     for part in path.components() {
         if found {
@@ -23,6 +29,53 @@ pub fn normalize_path(path: &Path) -> String {
     normalized_path.to_string_lossy().to_string()
 }
 
+fn is_inside(key: &str, folder: &str) -> bool {
+    let folder = folder.trim_matches('/');
+    key == folder || key.starts_with(&format!("{folder}/"))
+}
+
+/// Keys inside an included folder (any key when none is) and outside the excluded ones
+fn selects(include: &[String], exclude: &[String], key: &str) -> bool {
+    (include.is_empty() || include.iter().any(|folder| is_inside(key, folder)))
+        && !exclude.iter().any(|folder| is_inside(key, folder))
+}
+
+pub fn is_selected(key: &str) -> bool {
+    selects(&CONFIG.include, &CONFIG.exclude, key)
+}
+
+/// Passes that remove fewer files than this are always allowed
+const MIN_CHECKED_REMOVALS: usize = 5;
+
+pub fn exceeds_delete_threshold(count: usize, total: usize) -> bool {
+    count >= MIN_CHECKED_REMOVALS && count * 100 > total * CONFIG.max_delete_percent as usize
+}
+
+/// Suffix of the files being downloaded, they are renamed into place once complete
+const PARTIAL_SUFFIX: &str = ".rsink-part";
+
+pub fn is_partial(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(PARTIAL_SUFFIX))
+}
+
+/// Writes to a hidden file next to `path` and renames it into place,
+/// so an interrupted download never leaves a truncated file behind
+pub async fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let partial = path.with_file_name(format!(".{name}{PARTIAL_SUFFIX}"));
+    let mut file = tokio::fs::File::create(&partial).await?;
+
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    tokio::fs::rename(&partial, path).await?;
+
+    Ok(())
+}
+
+/// Lists the files under `dir`, Skipping the partial downloads
 pub fn walk_dir(dir: &Path) -> Result<Vec<DirEntry>> {
     let mut result = vec![];
 
@@ -33,7 +86,7 @@ pub fn walk_dir(dir: &Path) -> Result<Vec<DirEntry>> {
 
             if path.is_dir() {
                 result.append(&mut walk_dir(&path)?);
-            } else {
+            } else if !is_partial(&path) {
                 result.push(entry);
             }
         }
@@ -42,7 +95,24 @@ pub fn walk_dir(dir: &Path) -> Result<Vec<DirEntry>> {
     Ok(result)
 }
 
+lazy_static! {
+    static ref SETTINGS_FILE_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// Overrides the settings file, must be called before CONFIG is first used
+pub fn set_settings_file_path(path: PathBuf) {
+    *SETTINGS_FILE_PATH.lock().unwrap() = Some(path);
+}
+
+pub fn is_settings_file_overridden() -> bool {
+    SETTINGS_FILE_PATH.lock().unwrap().is_some()
+}
+
 pub fn settings_file_path() -> PathBuf {
+    if let Some(path) = SETTINGS_FILE_PATH.lock().unwrap().clone() {
+        return path;
+    }
+
     let mut path = dirs::config_dir().unwrap();
 
     path.push("rsink");
@@ -55,7 +125,59 @@ pub fn settings_file_path() -> PathBuf {
 }
 
 pub async fn check_connectivity() {
-    *IS_INTERNET_AVAILABLE.lock().unwrap() = online::check(None).await.is_ok();
+    let online = online::check(None).await.is_ok();
+
+    *IS_INTERNET_AVAILABLE.lock().unwrap() = online;
+    notification::connectivity(online);
+}
+
+pub fn content_hash(buffer: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    buffer.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Whether the event was caused by rsink itself writing/removing a downloaded file
+pub async fn is_expected_change(event: &Event) -> bool {
+    // A finished download is renamed from its partial file
+    let path = event.paths.last().unwrap();
+    let expected = match EXPECTED_CHANGES.get(path) {
+        Some(x) => *x,
+        None => return false,
+    };
+    let actual = tokio::fs::read(path)
+        .await
+        .ok()
+        .map(|buffer| content_hash(&buffer));
+
+    if actual == expected {
+        true
+    } else {
+        EXPECTED_CHANGES.remove(path);
+        false
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Formats a bytes per second limit, 0 means unlimited
+pub fn format_rate(rate: u64) -> String {
+    if rate == 0 {
+        "unlimited".to_owned()
+    } else {
+        format!("{}/s", format_size(rate))
+    }
 }
 
 pub fn log_error(err: anyhow::Error) -> Result<()> {
@@ -63,6 +185,24 @@ pub fn log_error(err: anyhow::Error) -> Result<()> {
     Ok(())
 }
 
+/// Logs the error and keeps the change in the dead-letter list to be retried later
+pub fn log_failure(key: &str, change: Change, err: anyhow::Error) -> Result<()> {
+    let op = format!("{change:?}").to_lowercase();
+    let error = format!("{err:#}");
+    log::error!(path = key, op = op.as_str(), error = error.as_str(); "Couldn't {op} {key}: {err:?}");
+    DEAD_LETTERS.push(key.to_owned(), change);
+    DEAD_LETTERS.save()
+}
+
+/// Forgets an earlier failure of the path once it synced successfully
+pub fn clear_failure(key: &str) -> Result<()> {
+    if DEAD_LETTERS.inner.remove(key).is_some() {
+        DEAD_LETTERS.save()?;
+    }
+
+    Ok(())
+}
+
 pub fn key_to_path(key: &str) -> PathBuf {
     let mut path = CONFIG.path.clone();
     path.push(key);
@@ -70,12 +210,93 @@ pub fn key_to_path(key: &str) -> PathBuf {
 }
 
 pub async fn metadata_of(path: &Path) -> (bool, u64, Option<OffsetDateTime>) {
-    if let Some(parent) = path.parent() {
-        tokio::fs::create_dir_all(parent).await.ok();
-    }
-
     tokio::fs::metadata(path)
         .await
         .map(|m| (true, m.len(), m.modified().map(|x| x.into()).ok()))
         .unwrap_or((false, 0, None))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing;
+
+    fn folders(names: &[&str]) -> Vec<String> {
+        names.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn selects_included_and_not_excluded_folders() {
+        let cases: [(&[&str], &[&str], &str, bool); 14] = [
+            // Everything by default
+            (&[], &[], "file", true),
+            (&[], &[], "Photos/a.jpg", true),
+            // Include only
+            (&["Documents"], &[], "Documents/a.txt", true),
+            (&["Documents"], &[], "Documents", true),
+            (&["Documents"], &[], "Photos/a.jpg", false),
+            (&["Documents"], &[], "DocumentsOld/a.txt", false),
+            // Exclude only
+            (&[], &["Photos"], "Photos/a.jpg", false),
+            (&[], &["Photos"], "Documents/a.txt", true),
+            // Both set
+            (&["Photos"], &["Photos/Raw"], "Photos/a.jpg", true),
+            (&["Photos"], &["Photos/Raw"], "Photos/Raw/a.cr2", false),
+            (&["Photos"], &["Photos/Raw"], "Documents/a.txt", false),
+            // Nested prefixes and slashes
+            (&["/Photos/Camera/"], &[], "Photos/Camera/a.jpg", true),
+            (&["Photos/Camera"], &[], "Photos/a.jpg", false),
+            (
+                &["Photos/Camera"],
+                &["Photos/Camera/2020"],
+                "Photos/Camera/2021/a.jpg",
+                true,
+            ),
+        ];
+
+        for (include, exclude, key, selected) in cases {
+            assert_eq!(
+                selects(&folders(include), &folders(exclude), key),
+                selected,
+                "include {include:?}, exclude {exclude:?}, key {key}"
+            );
+        }
+    }
+
+    #[test]
+    fn few_removals_are_always_allowed() {
+        testing::init();
+
+        assert!(!exceeds_delete_threshold(4, 4));
+        assert!(!exceeds_delete_threshold(0, 0));
+    }
+
+    #[test]
+    fn removals_above_the_percentage_are_refused() {
+        testing::init();
+
+        // max_delete_percent defaults to 50
+        assert!(!exceeds_delete_threshold(5, 10));
+        assert!(exceeds_delete_threshold(6, 10));
+        assert!(exceeds_delete_threshold(5, 5));
+    }
+
+    #[tokio::test]
+    async fn downloads_are_renamed_into_place() {
+        let dir = testing::dir("write_atomically");
+        let path = dir.join("file");
+
+        fs::write(dir.join(".file.rsink-part"), b"left over").unwrap();
+        write_atomically(&path, b"content").await.unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"content");
+        assert!(!dir.join(".file.rsink-part").exists());
+
+        fs::write(dir.join(".other.rsink-part"), b"interrupted").unwrap();
+
+        let files = walk_dir(&dir).unwrap();
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path(), path);
+    }
+}
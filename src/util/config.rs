@@ -1,43 +1,292 @@
 use crate::backends::*;
-use crate::util::settings_file_path;
+use crate::util::{is_settings_file_overridden, settings_file_path};
+use chrono::NaiveTime;
 use figment::{
     providers::{Format, Toml},
     Figment,
 };
 use notify_rust::Notification;
-use serde::Deserialize;
-use std::path::PathBuf;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{cmp::Ordering, path::PathBuf};
 
 fn default_interval() -> u64 {
     180000
 }
 
+fn default_debounce() -> u64 {
+    2000
+}
+
+fn default_workers() -> usize {
+    5
+}
+
+fn default_retries() -> u32 {
+    5
+}
+
+fn default_retry_delay() -> u64 {
+    1000
+}
+
+fn default_lease_ttl() -> u64 {
+    600
+}
+
+fn default_max_delete_percent() -> u8 {
+    50
+}
+
+fn default_tombstone_retention() -> u64 {
+    30
+}
+
 fn default_log_level() -> String {
     "info".to_owned()
 }
 
+fn default_log_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_log_max_files() -> usize {
+    5
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+    #[default]
+    Bidirectional,
+    UploadOnly,
+    DownloadOnly,
+    MirrorLocal,
+    MirrorRemote,
+}
+
+impl SyncMode {
+    pub fn can_upload(&self) -> bool {
+        !matches!(self, Self::DownloadOnly | Self::MirrorRemote)
+    }
+
+    pub fn can_download(&self) -> bool {
+        !matches!(self, Self::UploadOnly | Self::MirrorLocal)
+    }
+
+    /// Whether a local file that is missing from the cloud should be removed
+    pub fn should_remove_local(&self, deleted: bool) -> bool {
+        match self {
+            Self::Bidirectional | Self::DownloadOnly => deleted,
+            Self::UploadOnly | Self::MirrorLocal => false,
+            Self::MirrorRemote => true,
+        }
+    }
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&value, "%H:%M").map_err(serde::de::Error::custom)
+}
+
+/// A daily window of local time in which files of at least `min_size` bytes may be synced
+#[derive(Deserialize, Clone, Debug)]
+pub struct Window {
+    #[serde(deserialize_with = "deserialize_time")]
+    pub from: NaiveTime,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub to: NaiveTime,
+    #[serde(default)]
+    pub min_size: u64,
+}
+
+impl Window {
+    pub fn is_open(&self, now: NaiveTime) -> bool {
+        match self.from.cmp(&self.to) {
+            Ordering::Less => self.from <= now && now < self.to,
+            // Overnight, e.g. 22:00 - 06:00
+            Ordering::Greater => now >= self.from || now < self.to,
+            Ordering::Equal => true,
+        }
+    }
+}
+
+/// Which desktop notifications are shown by the daemon
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Notifications {
+    pub enabled: bool,
+    pub conflicts: bool,
+    /// Sync passes failing `failure_threshold` times in a row
+    pub failures: bool,
+    pub failure_threshold: u32,
+    /// No internet connection for `offline_after` seconds
+    pub offline: bool,
+    pub offline_after: u64,
+    /// The cloud storage running out of space
+    pub quota: bool,
+    /// Finished transfers of at least `large_transfer_size` bytes
+    pub large_transfers: bool,
+    pub large_transfer_size: u64,
+    /// Minimum seconds between two notifications of the same kind
+    pub min_interval: u64,
+}
+
+impl Default for Notifications {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            conflicts: true,
+            failures: true,
+            failure_threshold: 3,
+            offline: true,
+            offline_after: 3600,
+            quota: true,
+            large_transfers: true,
+            large_transfer_size: 100 * 1024 * 1024,
+            min_interval: 600,
+        }
+    }
+}
+
+fn default_hook_timeout() -> u64 {
+    300
+}
+
+/// Shell commands run around sync passes, see `util::hooks` for their environment variables
+#[derive(Deserialize, Clone, Debug)]
+pub struct Hooks {
+    /// Run before each sync pass, the pass is skipped when it fails
+    pub pre_sync: Option<String>,
+    pub post_sync: Option<String>,
+    /// Run after each file written from the cloud
+    pub post_download: Option<String>,
+    /// Seconds before a hook is killed
+    #[serde(default = "default_hook_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            pre_sync: None,
+            post_sync: None,
+            post_download: None,
+            timeout: default_hook_timeout(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// A finished sync pass
+    Pass,
+    /// An applied operation, as recorded in the audit log
+    File,
+}
+
+fn default_webhook_events() -> Vec<WebhookEvent> {
+    vec![WebhookEvent::Pass, WebhookEvent::File]
+}
+
+fn default_webhook_timeout() -> u64 {
+    10
+}
+
+/// POSTs JSON events to `url`, signed with `secret` when set
+#[derive(Deserialize, Clone, Debug)]
+pub struct Webhook {
+    pub url: String,
+    pub secret: Option<String>,
+    #[serde(default = "default_webhook_events")]
+    pub events: Vec<WebhookEvent>,
+    /// Seconds before a delivery attempt is given up
+    #[serde(default = "default_webhook_timeout")]
+    pub timeout: u64,
+}
+
 #[derive(Deserialize)]
 pub struct Config {
     pub path: PathBuf,
     #[serde(default = "default_log_level")]
     pub log: String,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Log to this file instead of stderr
+    pub log_file: Option<PathBuf>,
+    /// Bytes before the log file is rotated
+    #[serde(default = "default_log_max_size")]
+    pub log_max_size: u64,
+    #[serde(default = "default_log_max_files")]
+    pub log_max_files: usize,
     #[serde(default = "default_interval")]
     pub interval: u64,
+    #[serde(default = "default_debounce")]
+    pub debounce: u64,
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    #[serde(default = "default_retry_delay")]
+    pub retry_delay: u64,
+    pub device_name: Option<String>,
+    #[serde(default = "default_lease_ttl")]
+    pub lease_ttl: u64,
+    #[serde(default)]
+    pub mode: SyncMode,
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Folders left out even when inside an included one
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default = "default_max_delete_percent")]
+    pub max_delete_percent: u8,
+    /// Days before the tombstone of a deleted file is dropped, 0 keeps them forever
+    #[serde(default = "default_tombstone_retention")]
+    pub tombstone_retention: u64,
+    #[serde(default)]
+    pub schedule: Vec<Window>,
+    /// Bytes per second, 0 means unlimited
+    #[serde(default)]
+    pub max_upload_rate: u64,
+    #[serde(default)]
+    pub max_download_rate: u64,
+    /// Address of the Prometheus metrics listener, disabled by default
+    pub metrics: Option<String>,
+    #[serde(default)]
+    pub notifications: Notifications,
+    #[serde(default)]
+    pub hooks: Hooks,
+    pub webhook: Option<Webhook>,
     pub backend: BackendOptions,
 }
 
+pub fn load_config() -> Result<Config> {
+    let figment = Figment::new().merge(Toml::file(settings_file_path()));
+
+    if is_settings_file_overridden() {
+        Ok(figment.extract()?)
+    } else {
+        Ok(figment.merge(Toml::file("rsink.conf")).extract()?)
+    }
+}
+
 lazy_static! {
-    pub static ref CONFIG: Config = Figment::new()
-        .merge(Toml::file(settings_file_path()))
-        .merge(Toml::file("rsink.conf"))
-        .extract()
-        .unwrap_or_else(|err| {
-            Notification::new()
-                .summary("RSink")
-                .body("Please configure correctly the missing settings for RSink to work")
-                .icon("dialog-error")
-                .show()
-                .ok();
-            panic!("Missing/Invalid configuration: {err}");
-        });
+    pub static ref CONFIG: Config = load_config().unwrap_or_else(|err| {
+        Notification::new()
+            .summary("RSink")
+            .body("Please configure correctly the missing settings for RSink to work")
+            .icon("dialog-error")
+            .show()
+            .ok();
+        panic!("Missing/Invalid configuration: {err:#}");
+    });
 }
@@ -0,0 +1,220 @@
+use notify::{event::*, Event};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+type Snapshot = (u64, Option<SystemTime>);
+
+struct Pending {
+    last_seen: Instant,
+    snapshot: Option<Snapshot>,
+}
+
+/// Coalesces bursts of events per path and holds them back until the file stops changing
+pub struct Debouncer {
+    window: Duration,
+    pending: HashMap<PathBuf, Pending>,
+}
+
+async fn snapshot_of(path: &Path) -> Option<Snapshot> {
+    tokio::fs::metadata(path)
+        .await
+        .ok()
+        .map(|m| (m.len(), m.modified().ok()))
+}
+
+fn settled_event(path: PathBuf) -> Option<Event> {
+    let kind = if path.is_file() {
+        EventKind::Create(CreateKind::File)
+    } else if !path.exists() {
+        EventKind::Remove(RemoveKind::Any)
+    } else {
+        return None;
+    };
+
+    Some(Event::new(kind).add_path(path))
+}
+
+impl Debouncer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn tick_period(&self) -> Duration {
+        self.window.max(Duration::from_millis(100))
+    }
+
+    /// Returns the events that must be handled right away
+    pub fn push(&mut self, event: Event) -> Vec<Event> {
+        match event.kind {
+            EventKind::Access(AccessKind::Close(AccessMode::Write))
+            | EventKind::Create(_)
+            | EventKind::Remove(_)
+            | EventKind::Modify(
+                ModifyKind::Data(_) | ModifyKind::Metadata(MetadataKind::WriteTime),
+            ) => {}
+            EventKind::Modify(ModifyKind::Name(_)) if event.paths.len() == 2 => {
+                // A rename supersedes whatever was pending on both ends
+                self.pending.remove(&event.paths[0]);
+                self.pending.remove(&event.paths[1]);
+                return vec![event];
+            }
+            EventKind::Modify(ModifyKind::Name(_)) => {}
+            _ => return vec![],
+        }
+
+        let now = Instant::now();
+
+        for path in event.paths {
+            self.pending
+                .entry(path)
+                .or_insert(Pending {
+                    last_seen: now,
+                    snapshot: None,
+                })
+                .last_seen = now;
+        }
+
+        vec![]
+    }
+
+    /// Returns one coalesced event per path that has settled down
+    pub async fn flush(&mut self) -> Vec<Event> {
+        let now = Instant::now();
+        let mut ready = vec![];
+
+        for (path, pending) in self.pending.iter_mut() {
+            if now.duration_since(pending.last_seen) < self.window {
+                continue;
+            }
+
+            let snapshot = snapshot_of(path).await;
+
+            if snapshot.is_some() && snapshot != pending.snapshot {
+                log::debug!("{:?} is still changing, Waiting...", path);
+                pending.snapshot = snapshot;
+                pending.last_seen = now;
+                continue;
+            }
+
+            ready.push(path.clone());
+        }
+
+        ready
+            .into_iter()
+            .filter_map(|path| {
+                self.pending.remove(&path);
+                settled_event(path)
+            })
+            .collect()
+    }
+
+    /// Returns the coalesced events of every pending path without waiting
+    pub fn drain(&mut self) -> Vec<Event> {
+        self.pending
+            .drain()
+            .filter_map(|(path, _)| settled_event(path))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing;
+    use std::fs;
+    use tokio::time::sleep;
+
+    const WINDOW: Duration = Duration::from_millis(50);
+
+    fn event(kind: EventKind, paths: &[&Path]) -> Event {
+        paths.iter().fold(Event::new(kind), |event, path| {
+            event.add_path(path.to_path_buf())
+        })
+    }
+
+    /// Flushes until the debouncer gives up the events, the first flush only snapshots them
+    async fn settle(debouncer: &mut Debouncer) -> Vec<Event> {
+        for _ in 0..5 {
+            sleep(WINDOW).await;
+
+            let events = debouncer.flush().await;
+
+            if !events.is_empty() {
+                return events;
+            }
+        }
+
+        vec![]
+    }
+
+    #[tokio::test]
+    async fn bursts_are_coalesced_into_one_event() {
+        let path = testing::dir("debounce_burst").join("file");
+        let mut debouncer = Debouncer::new(WINDOW);
+
+        fs::write(&path, b"a").unwrap();
+        assert!(debouncer
+            .push(event(EventKind::Create(CreateKind::File), &[&path]))
+            .is_empty());
+
+        for _ in 0..3 {
+            let kind = EventKind::Modify(ModifyKind::Data(DataChange::Any));
+            assert!(debouncer.push(event(kind, &[&path])).is_empty());
+        }
+
+        assert!(debouncer.flush().await.is_empty(), "The window isn't over");
+
+        let events = settle(&mut debouncer).await;
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::Create(CreateKind::File));
+        assert_eq!(events[0].paths, [path]);
+        assert!(debouncer.drain().is_empty());
+    }
+
+    #[tokio::test]
+    async fn removed_files_settle_as_removals() {
+        let path = testing::dir("debounce_remove").join("file");
+        let mut debouncer = Debouncer::new(WINDOW);
+
+        debouncer.push(event(EventKind::Create(CreateKind::File), &[&path]));
+        debouncer.push(event(EventKind::Remove(RemoveKind::File), &[&path]));
+
+        let events = settle(&mut debouncer).await;
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::Remove(RemoveKind::Any));
+    }
+
+    #[tokio::test]
+    async fn renames_pass_through_and_drop_pending_events() {
+        let dir = testing::dir("debounce_rename");
+        let (from, to) = (dir.join("from"), dir.join("to"));
+        let mut debouncer = Debouncer::new(WINDOW);
+
+        debouncer.push(event(EventKind::Create(CreateKind::File), &[&from]));
+
+        let kind = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+        let events = debouncer.push(event(kind, &[&from, &to]));
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].paths, [from, to]);
+        assert!(debouncer.drain().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reads_are_ignored() {
+        let path = testing::dir("debounce_read").join("file");
+        let mut debouncer = Debouncer::new(WINDOW);
+
+        let kind = EventKind::Access(AccessKind::Open(AccessMode::Read));
+        assert!(debouncer.push(event(kind, &[&path])).is_empty());
+        assert!(debouncer.drain().is_empty());
+    }
+}
@@ -0,0 +1,66 @@
+use crate::backends::Device;
+use crate::config::CONFIG;
+use std::{
+    collections::hash_map::RandomState,
+    fs,
+    hash::{BuildHasher, Hasher},
+    path::PathBuf,
+};
+use time::OffsetDateTime;
+
+lazy_static! {
+    pub static ref DEVICE_ID: String = load_or_create("device_id", random_id);
+    pub static ref DEVICE_NAME: String = CONFIG
+        .device_name
+        .clone()
+        .unwrap_or_else(|| load_or_create("device_name", hostname));
+}
+
+pub fn current_device() -> Device {
+    Device {
+        id: DEVICE_ID.clone(),
+        name: DEVICE_NAME.clone(),
+        last_seen: OffsetDateTime::now_utc().unix_timestamp(),
+    }
+}
+
+pub fn data_path(name: &str) -> PathBuf {
+    let mut path = dirs::data_dir().unwrap();
+
+    path.push("rsink");
+
+    fs::create_dir_all(&path).ok();
+
+    path.push(name);
+
+    path
+}
+
+fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+fn random_id() -> String {
+    let random = || RandomState::new().build_hasher().finish();
+    format!("{:016x}{:016x}", random(), random())
+}
+
+/// Reads a persistent value, creating it on first use
+fn load_or_create(name: &str, create: fn() -> String) -> String {
+    let path = data_path(name);
+
+    match fs::read_to_string(&path) {
+        Ok(value) if !value.trim().is_empty() => value.trim().to_owned(),
+        _ => {
+            let value = create();
+            fs::write(&path, &value).expect("Couldn't save device identity");
+            value
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use super::{config::CONFIG, device::*};
+use anyhow::{bail, Result};
+use std::{path::Path, time::Duration};
+use tokio::{process::Command, time::timeout};
+
+#[derive(Clone, Copy, Debug)]
+pub enum Hook {
+    PreSync,
+    PostSync,
+    PostDownload,
+}
+
+impl Hook {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::PreSync => "pre_sync",
+            Self::PostSync => "post_sync",
+            Self::PostDownload => "post_download",
+        }
+    }
+
+    fn command(&self) -> Option<&'static str> {
+        match self {
+            Self::PreSync => CONFIG.hooks.pre_sync.as_deref(),
+            Self::PostSync => CONFIG.hooks.post_sync.as_deref(),
+            Self::PostDownload => CONFIG.hooks.post_download.as_deref(),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Runs the command configured for the hook, if any, and fails when it exits with an error
+///
+/// Besides `vars` (prefixed with `RSINK_`), the command gets `RSINK_HOOK`, `RSINK_ROOT`,
+/// `RSINK_DEVICE` and `RSINK_DEVICE_ID`
+pub async fn run(hook: Hook, vars: &[(&str, String)]) -> Result<()> {
+    let command = match hook.command() {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+
+    log::debug!("Running the {} hook: {command}", hook.name());
+
+    let mut process = shell(command);
+
+    process
+        .env("RSINK_HOOK", hook.name())
+        .env("RSINK_ROOT", &CONFIG.path)
+        .env("RSINK_DEVICE", &*DEVICE_NAME)
+        .env("RSINK_DEVICE_ID", &*DEVICE_ID)
+        .kill_on_drop(true);
+
+    for (key, value) in vars {
+        process.env(format!("RSINK_{key}"), value);
+    }
+
+    let status = match timeout(Duration::from_secs(CONFIG.hooks.timeout), process.status()).await {
+        Ok(status) => status?,
+        Err(_) => bail!(
+            "The {} hook didn't finish in {}s",
+            hook.name(),
+            CONFIG.hooks.timeout
+        ),
+    };
+
+    if !status.success() {
+        bail!("The {} hook failed with {status}", hook.name());
+    }
+
+    Ok(())
+}
+
+/// Runs the post_download hook for a file written to `path`, failures are only logged
+pub async fn downloaded(path: &Path, key: &str, size: usize) {
+    let vars = [
+        ("FILE", path.to_string_lossy().into_owned()),
+        ("KEY", key.to_owned()),
+        ("SIZE", size.to_string()),
+    ];
+
+    if let Err(err) = run(Hook::PostDownload, &vars).await {
+        log::warn!("{err:#}");
+    }
+}
@@ -0,0 +1,54 @@
+use super::cache::cache_path;
+use crate::config::CONFIG;
+use anyhow::{bail, Result};
+use fs2::FileExt;
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+/// Held for the whole lifetime of the daemon, the lock is released when the file is closed
+pub struct InstanceLock {
+    _file: File,
+}
+
+/// Describes the running instance holding the lock, if any
+pub fn holder() -> Option<String> {
+    let mut file = File::open(cache_path("lock")).ok()?;
+
+    if file.try_lock_shared().is_ok() {
+        file.unlock().ok();
+        return None;
+    }
+
+    let mut owner = String::new();
+    file.read_to_string(&mut owner).ok();
+    Some(owner.trim().to_owned())
+}
+
+/// Makes sure no other rsink instance shares the same cache (and sync root)
+pub fn acquire() -> Result<InstanceLock> {
+    let path = cache_path("lock");
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+
+    if file.try_lock_exclusive().is_err() {
+        let mut owner = String::new();
+        file.read_to_string(&mut owner).ok();
+        bail!(
+            "Another rsink instance is already running ({}), Lock file: {:?}",
+            owner.trim(),
+            path
+        );
+    }
+
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    writeln!(file, "pid {} syncing {:?}", std::process::id(), CONFIG.path)?;
+
+    Ok(InstanceLock { _file: file })
+}
@@ -0,0 +1,227 @@
+use super::config::{LogFormat, CONFIG};
+use env_logger::{fmt::Formatter, Target};
+use log::{
+    kv::{self, Key, Value, VisitSource},
+    LevelFilter, Record,
+};
+use serde_json::{json, Map};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// A log file that is renamed to `<name>.1` once it grows past `max_size`,
+/// older files are shifted up to `<name>.<max_files>` and then dropped
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = File::options().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{index}"));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.max_files).rev() {
+            fs::rename(self.rotated(index), self.rotated(index + 1)).ok();
+        }
+
+        if self.max_files > 0 {
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        self.file = File::options()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Collects the structured fields of a record, e.g. `log::info!(path = key; "...")`
+#[derive(Default)]
+struct Fields(Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(x) = value.to_u64() {
+            json!(x)
+        } else if let Some(x) = value.to_i64() {
+            json!(x)
+        } else if let Some(x) = value.to_f64() {
+            json!(x)
+        } else if let Some(x) = value.to_bool() {
+            json!(x)
+        } else {
+            json!(value.to_string())
+        };
+
+        self.0.insert(key.to_string(), value);
+
+        Ok(())
+    }
+}
+
+fn fields_of(record: &Record) -> Map<String, serde_json::Value> {
+    let mut fields = Fields::default();
+    record.key_values().visit(&mut fields).ok();
+    fields.0
+}
+
+fn format_json(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let mut entry = Map::new();
+
+    entry.insert(
+        "ts".to_owned(),
+        json!(OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default()),
+    );
+    entry.insert("level".to_owned(), json!(record.level().as_str()));
+    entry.insert("target".to_owned(), json!(record.target()));
+    entry.insert("msg".to_owned(), json!(record.args().to_string()));
+    entry.extend(fields_of(record));
+
+    writeln!(buf, "{}", serde_json::Value::Object(entry))
+}
+
+fn format_text(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    // There is no terminal adding timestamps to the log file
+    if CONFIG.log_file.is_some() {
+        write!(buf, "{} ", buf.timestamp_seconds())?;
+    }
+
+    write!(
+        buf,
+        "[{:<5} {}] {}",
+        buf.default_styled_level(record.level()),
+        record.target(),
+        record.args()
+    )?;
+
+    for (key, value) in fields_of(record) {
+        match value {
+            serde_json::Value::String(value) => write!(buf, " {key}={value:?}")?,
+            value => write!(buf, " {key}={value}")?,
+        }
+    }
+
+    writeln!(buf)
+}
+
+pub fn init() {
+    let mut builder = env_logger::builder();
+
+    builder
+        .parse_filters("serde_xml_rs=off,rustls=off,mio=off,want=off")
+        .filter_level(LevelFilter::from_str(&CONFIG.log).expect("Invalid log level format"));
+
+    match CONFIG.log_format {
+        LogFormat::Text => builder.format(format_text),
+        LogFormat::Json => builder.format(format_json),
+    };
+
+    if let Some(path) = &CONFIG.log_file {
+        let file = RotatingFile::open(path.clone(), CONFIG.log_max_size, CONFIG.log_max_files)
+            .expect("Couldn't open the log file");
+        builder.target(Target::Pipe(Box::new(file)));
+    }
+
+    builder.init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing;
+
+    #[test]
+    fn rotates_past_max_size_and_drops_the_oldest() {
+        let path = testing::dir("rotating_file").join("rsink.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        let rotated = |index: usize| path.with_file_name(format!("rsink.log.{index}"));
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(rotated(1)).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(rotated(2)).unwrap(), "second\n");
+        assert!(!rotated(3).exists());
+    }
+
+    #[test]
+    fn small_writes_share_a_file() {
+        let path = testing::dir("rotating_file_small").join("rsink.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+
+        file.write_all(b"ab\n").unwrap();
+        file.write_all(b"cd\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "ab\ncd\n");
+        assert!(!path.with_file_name("rsink.log.1").exists());
+    }
+
+    #[test]
+    fn appends_to_an_existing_file() {
+        let path = testing::dir("rotating_file_existing").join("rsink.log");
+
+        fs::write(&path, "0123456789").unwrap();
+
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        file.write_all(b"next\n").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "next\n");
+        assert_eq!(
+            fs::read_to_string(path.with_file_name("rsink.log.1")).unwrap(),
+            "0123456789"
+        );
+    }
+}
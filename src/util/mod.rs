@@ -1,4 +1,19 @@
+pub mod activity;
+pub mod audit;
 pub mod cache;
 pub mod common;
 pub mod config;
+pub mod debounce;
+pub mod device;
+pub mod hooks;
+pub mod lock;
+pub mod logging;
+pub mod notification;
+pub mod queue;
+pub mod schedule;
+pub mod scheduler;
+pub mod shutdown;
+#[cfg(test)]
+pub mod testing;
+pub mod webhook;
 pub use common::*;
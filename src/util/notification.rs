@@ -0,0 +1,204 @@
+use super::{activity::Action, config::CONFIG, format_size};
+use crate::backends::HttpError;
+use dashmap::DashMap;
+use notify_rust::Notification;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+lazy_static! {
+    static ref ENABLED: AtomicBool = AtomicBool::new(false);
+    static ref SHOWN_AT: DashMap<Event, Instant> = DashMap::new();
+    static ref FAILED_PASSES: AtomicU32 = AtomicU32::new(0);
+    /// When the connection was lost and whether it was notified already
+    static ref OFFLINE_SINCE: Mutex<Option<(Instant, bool)>> = Mutex::new(None);
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Event {
+    Conflict,
+    Failure,
+    Offline,
+    Quota,
+    LargeTransfer,
+}
+
+/// Shows the notifications from now on, only the daemon does it
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn is_wanted(event: Event) -> bool {
+    let settings = &CONFIG.notifications;
+
+    ENABLED.load(Ordering::Relaxed)
+        && settings.enabled
+        && match event {
+            Event::Conflict => settings.conflicts,
+            Event::Failure => settings.failures,
+            Event::Offline => settings.offline,
+            Event::Quota => settings.quota,
+            Event::LargeTransfer => settings.large_transfers,
+        }
+}
+
+/// Shows a notification unless one of the same kind was shown in the last `min_interval` seconds
+fn show(event: Event, body: String, icon: &'static str) {
+    if !is_wanted(event) {
+        return;
+    }
+
+    let min_interval = Duration::from_secs(CONFIG.notifications.min_interval);
+
+    let shown_recently = SHOWN_AT
+        .get(&event)
+        .is_some_and(|shown_at| shown_at.elapsed() < min_interval);
+
+    if shown_recently {
+        log::debug!("Skip {event:?} notification, One was shown recently");
+        return;
+    }
+
+    SHOWN_AT.insert(event, Instant::now());
+
+    // Talking to the notification server blocks
+    thread::spawn(move || {
+        if let Err(err) = Notification::new()
+            .summary("RSink")
+            .body(&body)
+            .icon(icon)
+            .show()
+        {
+            log::debug!("Couldn't show the notification: {err}");
+        }
+    });
+}
+
+pub fn conflict(key: &str, writer: Option<&str>) {
+    let by = writer.map(|x| format!(" by {x}")).unwrap_or_default();
+
+    show(
+        Event::Conflict,
+        format!("{key} was changed locally and in the cloud{by}, The newer version was kept"),
+        "dialog-warning",
+    );
+}
+
+/// Counts the sync passes failing in a row, `failure_threshold` of them are notified
+pub fn pass_finished(ok: bool) {
+    if ok {
+        FAILED_PASSES.store(0, Ordering::Relaxed);
+        return;
+    }
+
+    let failed = FAILED_PASSES.fetch_add(1, Ordering::Relaxed) + 1;
+
+    if failed >= CONFIG.notifications.failure_threshold {
+        show(
+            Event::Failure,
+            format!("Syncing failed {failed} times in a row, See the logs for details"),
+            "dialog-error",
+        );
+    }
+}
+
+/// Notifies once the connection has been lost for `offline_after` seconds
+pub fn connectivity(online: bool) {
+    let mut offline_since = OFFLINE_SINCE.lock().unwrap();
+
+    match offline_since.as_mut() {
+        _ if online => *offline_since = None,
+        None => *offline_since = Some((Instant::now(), false)),
+        Some((since, notified)) => {
+            let offline_after = Duration::from_secs(CONFIG.notifications.offline_after);
+
+            if !*notified && since.elapsed() >= offline_after {
+                *notified = true;
+                show(
+                    Event::Offline,
+                    format!(
+                        "Syncing is paused, There has been no internet connection for {} minute(s)",
+                        since.elapsed().as_secs() / 60
+                    ),
+                    "network-offline",
+                );
+            }
+        }
+    }
+}
+
+/// Whether the error means the bucket is out of space, Either a 507 status or
+/// an S3 error code like `QuotaExceeded` in its context
+pub fn is_quota_error(err: &anyhow::Error) -> bool {
+    let insufficient_storage = err
+        .chain()
+        .any(|cause| matches!(cause.downcast_ref::<HttpError>(), Some(HttpError(507))));
+    let error = format!("{err:#}").to_lowercase();
+
+    insufficient_storage
+        || [
+            "quotaexceeded",
+            "insufficientstorage",
+            "storagequotaexceeded",
+            "storage cap",
+        ]
+        .iter()
+        .any(|x| error.contains(x))
+}
+
+pub fn transfer_failed(key: &str, err: &anyhow::Error) {
+    if is_quota_error(err) {
+        show(
+            Event::Quota,
+            format!("The cloud storage is full, Couldn't sync {key}"),
+            "dialog-error",
+        );
+    }
+}
+
+pub fn transfer_finished(key: &str, action: Action, bytes: u64) {
+    let size = CONFIG.notifications.large_transfer_size;
+
+    if !matches!(action, Action::Upload | Action::Download) || size == 0 || bytes < size {
+        return;
+    }
+
+    let done = match action {
+        Action::Upload => "uploaded",
+        _ => "downloaded",
+    };
+
+    show(
+        Event::LargeTransfer,
+        format!("{key} ({}) was {done}", format_size(bytes)),
+        "dialog-information",
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn detects_quota_errors() {
+        let insufficient_storage = Err::<(), _>(HttpError(507)).context("Couldn't upload");
+        let quota_exceeded = Err::<(), _>(HttpError(403)).context("QuotaExceeded: Bucket is full");
+
+        assert!(is_quota_error(&insufficient_storage.unwrap_err()));
+        assert!(is_quota_error(&quota_exceeded.unwrap_err()));
+    }
+
+    #[test]
+    fn ignores_other_errors() {
+        let forbidden = Err::<(), _>(HttpError(403)).context("AccessDenied: Access Denied");
+
+        assert!(!is_quota_error(&forbidden.unwrap_err()));
+        assert!(!is_quota_error(&anyhow!("connection reset")));
+    }
+}
@@ -0,0 +1,368 @@
+use super::cache::cache_path;
+use crate::backends::{Backend, Operation};
+use crate::config::CONFIG;
+use crate::util::{
+    activity, clear_failure, content_hash, hooks, is_selected, key_to_path, log_failure,
+    normalize_path, schedule, shutdown, write_atomically,
+};
+use crate::{EXPECTED_CHANGES, SYNCED_PATHS, SYNCED_STATES};
+use anyhow::Result;
+use dashmap::DashMap;
+use notify::{event::*, Event};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Upload,
+    Download,
+    Remove,
+}
+
+impl Change {
+    pub fn of(op: &Operation) -> Option<Self> {
+        match op {
+            Operation::Upload(_) => Some(Change::Upload),
+            Operation::Write(..) | Operation::WriteEmpty(_) => Some(Change::Download),
+            Operation::Remove(_) => Some(Change::Remove),
+            Operation::Checked(_)
+            | Operation::Deleted(_)
+            | Operation::Conflict(_)
+            | Operation::RemoveLocal(_) => None,
+        }
+    }
+
+    /// `size` is the one of the cloud object, Only used by downloads
+    pub fn to_operation(self, path: PathBuf, size: u64) -> Operation {
+        match self {
+            Change::Upload => Operation::Upload(path),
+            Change::Download => Operation::Write(path, size),
+            Change::Remove => Operation::Remove(path),
+        }
+    }
+}
+
+/// Failed replays of a change before it's moved to the dead letters
+const MAX_REPLAY_ATTEMPTS: u32 = 3;
+
+/// Outcome of a replay
+#[derive(Default, Debug)]
+pub struct Replayed {
+    pub replayed: usize,
+    /// Changes kept queued until their sync window
+    pub deferred: usize,
+}
+
+/// Local changes that couldn't be synced yet, coalesced per path
+pub struct Queue {
+    path: PathBuf,
+    pub inner: DashMap<String, Change>,
+    /// Failed replays per key since the last success
+    attempts: DashMap<String, u32>,
+}
+
+impl Queue {
+    pub fn new(name: &str) -> Self {
+        let path = cache_path(name);
+        let map: BTreeMap<String, Change> = fs::read(&path)
+            .ok()
+            .and_then(|buffer| serde_json::from_slice(&buffer).ok())
+            .unwrap_or_default();
+
+        Self {
+            inner: DashMap::from_iter(map),
+            path,
+            attempts: DashMap::new(),
+        }
+    }
+
+    pub fn push(&self, key: String, change: Change) {
+        if change == Change::Remove
+            && !SYNCED_PATHS.inner.contains(&key)
+            && self.inner.get(&key).map(|x| *x) == Some(Change::Upload)
+        {
+            // Created and removed while offline, the cloud never knew about it
+            self.inner.remove(&key);
+        } else {
+            self.inner.insert(key, change);
+        }
+    }
+
+    /// Queues the removal of a path synced by this device, The cloud copy of any other
+    /// may belong to another device
+    fn push_removal(&self, key: String) {
+        if SYNCED_PATHS.inner.contains(&key) {
+            self.push(key, Change::Remove);
+        } else {
+            self.inner.remove(&key);
+        }
+    }
+
+    pub fn push_event(&self, event: &Event) {
+        let path = &event.paths[0];
+        let key = normalize_path(path);
+
+        match event.kind {
+            EventKind::Create(_) if path.is_file() => self.push(key, Change::Upload),
+            EventKind::Remove(_) => self.push_removal(key),
+            EventKind::Access(AccessKind::Close(AccessMode::Write))
+            | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Metadata(_)) => {
+                if path.is_file() {
+                    self.push(key, Change::Upload)
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(_)) if event.paths.len() == 2 => {
+                self.push_removal(key);
+                self.push(normalize_path(&event.paths[1]), Change::Upload);
+            }
+            _ => return,
+        }
+
+        self.save().ok();
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, Change> {
+        self.inner
+            .iter()
+            .map(|x| (x.key().clone(), *x.value()))
+            .collect()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let map = self.snapshot();
+        fs::write(&self.path, serde_json::to_string(&map)?)?;
+        log::debug!("Saved queue file includes {} item", map.len());
+        Ok(())
+    }
+
+    /// Apply the queued changes to the cloud, failed ones are kept for the next replay
+    /// and moved to the dead letters after `MAX_REPLAY_ATTEMPTS`
+    pub async fn replay<B: Backend + Sync>(&self, cloud: &B) -> Result<Replayed> {
+        let changes = self
+            .inner
+            .iter()
+            .map(|x| (x.key().clone(), *x.value()))
+            .collect::<Vec<_>>();
+        let mut replayed = Replayed::default();
+        // Sizes of the cloud objects, Listed once for the windows of the downloads
+        let mut sizes: Option<HashMap<String, u64>> = None;
+
+        for (key, change) in changes {
+            if shutdown::is_requested() {
+                break;
+            }
+
+            if !is_selected(&key) {
+                self.inner.remove(&key);
+                continue;
+            }
+
+            let path = key_to_path(&key);
+
+            let size = match change {
+                Change::Download if !CONFIG.schedule.is_empty() => {
+                    if sizes.is_none() {
+                        let objects = cloud.list("").await?;
+                        sizes = Some(objects.into_iter().map(|x| (x.key, x.size)).collect());
+                    }
+                    sizes
+                        .as_ref()
+                        .and_then(|x| x.get(&key))
+                        .copied()
+                        .unwrap_or(0)
+                }
+                _ => 0,
+            };
+
+            if !schedule::allows_operation(&change.to_operation(path.clone(), size)) {
+                log::debug!("Keeping {change:?} of {key} queued until its sync window");
+                replayed.deferred += 1;
+                continue;
+            }
+
+            let result = match change {
+                Change::Upload if path.is_file() => {
+                    log::debug!("Replaying upload of {path:?}");
+                    match tokio::fs::read(&path).await {
+                        Ok(buffer) => cloud.upload(&key, &buffer).await.map(|_| {
+                            SYNCED_PATHS.inner.insert(key.clone());
+                            SYNCED_STATES.record(&key, &path);
+                        }),
+                        Err(err) => Err(err.into()),
+                    }
+                }
+                Change::Upload => Ok(()),
+                Change::Download => {
+                    log::debug!("Replaying download of {path:?}");
+                    activity::expect_size(&key, size);
+                    match cloud.download(&key).await {
+                        Ok(buffer) => {
+                            if let Some(parent) = path.parent() {
+                                tokio::fs::create_dir_all(parent).await.ok();
+                            }
+                            EXPECTED_CHANGES.insert(path.clone(), Some(content_hash(&buffer)));
+                            match write_atomically(&path, &buffer).await {
+                                Ok(_) => {
+                                    SYNCED_PATHS.inner.insert(key.clone());
+                                    SYNCED_STATES.record(&key, &path);
+                                    hooks::downloaded(&path, &key, buffer.len()).await;
+                                    Ok(())
+                                }
+                                err => err,
+                            }
+                        }
+                        Err(err) => Err(err),
+                    }
+                }
+                Change::Remove => {
+                    log::debug!("Replaying removal of {path:?}");
+                    cloud.remove(&key).await.map(|_| {
+                        SYNCED_PATHS.inner.remove(&key);
+                        SYNCED_STATES.inner.remove(&key);
+                    })
+                }
+            };
+
+            match result {
+                Ok(_) => {
+                    // A newer change queued meanwhile is kept for the next replay
+                    self.inner.remove_if(&key, |_, queued| *queued == change);
+                    self.attempts.remove(&key);
+
+                    if change == Change::Upload && !path.exists() && !self.inner.contains_key(&key)
+                    {
+                        // Removed while its first upload was running, so the removal was dropped
+                        self.push_removal(key.clone());
+                    }
+                    clear_failure(&key)?;
+                    replayed.replayed += 1;
+                }
+                Err(err) => {
+                    let attempts = {
+                        let mut attempts = self.attempts.entry(key.clone()).or_insert(0);
+                        *attempts += 1;
+                        *attempts
+                    };
+
+                    if attempts < MAX_REPLAY_ATTEMPTS {
+                        log::warn!("Couldn't replay {change:?} of {key} ({attempts}/{MAX_REPLAY_ATTEMPTS}): {err:#}");
+                    } else {
+                        self.inner.remove_if(&key, |_, queued| *queued == change);
+                        self.attempts.remove(&key);
+                        log_failure(&key, change, err)?;
+                    }
+                }
+            }
+        }
+
+        self.save()?;
+        SYNCED_PATHS.save()?;
+        SYNCED_STATES.save()?;
+
+        Ok(replayed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::{self, FakeCloud};
+    use crate::DEAD_LETTERS;
+    use std::sync::atomic::Ordering;
+
+    fn removal(key: &str) -> Event {
+        Event::new(EventKind::Remove(RemoveKind::File)).add_path(key_to_path(key))
+    }
+
+    #[tokio::test]
+    async fn only_synced_paths_are_removed_from_the_cloud() {
+        let _sync_dir = testing::sync_dir().await;
+        let queue = Queue::new("test_removals");
+
+        SYNCED_PATHS.inner.insert("synced".to_owned());
+        queue.push("created".to_owned(), Change::Upload);
+
+        queue.push_event(&removal("synced"));
+        queue.push_event(&removal("created"));
+        queue.push_event(&removal("other_device"));
+
+        assert_eq!(
+            queue.snapshot(),
+            BTreeMap::from([("synced".to_owned(), Change::Remove)])
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_replays_move_to_the_dead_letters() {
+        let _sync_dir = testing::sync_dir().await;
+        let queue = Queue::new("test_failing_replays");
+        let cloud = FakeCloud::default();
+
+        cloud
+            .failures
+            .store(MAX_REPLAY_ATTEMPTS as usize, Ordering::Relaxed);
+        queue.push("failing".to_owned(), Change::Remove);
+
+        for _ in 1..MAX_REPLAY_ATTEMPTS {
+            assert_eq!(queue.replay(&cloud).await.unwrap().replayed, 0);
+            assert!(queue.inner.contains_key("failing"));
+            assert!(!DEAD_LETTERS.inner.contains_key("failing"));
+        }
+
+        assert_eq!(queue.replay(&cloud).await.unwrap().replayed, 0);
+        assert!(queue.inner.is_empty());
+        assert_eq!(
+            DEAD_LETTERS.inner.get("failing").map(|x| *x),
+            Some(Change::Remove)
+        );
+        assert_eq!(cloud.calls().len(), MAX_REPLAY_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn changes_queued_during_the_replay_are_kept() {
+        let _sync_dir = testing::sync_dir().await;
+
+        lazy_static! {
+            static ref QUEUE: Queue = Queue::new("test_replay_meanwhile");
+        }
+
+        let cloud = FakeCloud {
+            // The user removes the files while their uploads are replaying
+            during_transfer: Some(Box::new(|key| {
+                std::fs::remove_file(key_to_path(key)).ok();
+                QUEUE.push_event(&removal(key));
+            })),
+            ..Default::default()
+        };
+
+        for key in ["edited", "created"] {
+            std::fs::write(key_to_path(key), key).unwrap();
+            QUEUE.push(key.to_owned(), Change::Upload);
+        }
+
+        SYNCED_PATHS.inner.insert("edited".to_owned());
+
+        assert_eq!(QUEUE.replay(&cloud).await.unwrap().replayed, 2);
+        assert_eq!(
+            QUEUE.snapshot(),
+            BTreeMap::from([
+                ("created".to_owned(), Change::Remove),
+                ("edited".to_owned(), Change::Remove),
+            ])
+        );
+
+        cloud.calls.lock().unwrap().clear();
+
+        assert_eq!(QUEUE.replay(&cloud).await.unwrap().replayed, 2);
+        assert!(QUEUE.inner.is_empty());
+
+        let mut calls = cloud.calls();
+        calls.sort();
+        assert_eq!(calls, ["remove created", "remove edited"]);
+    }
+}
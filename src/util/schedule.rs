@@ -0,0 +1,104 @@
+use crate::backends::Operation;
+use crate::config::{Window, CONFIG};
+use chrono::{Local, NaiveTime};
+use std::path::Path;
+
+/// Only the most specific windows apply, the ones with the highest `min_size` not above `size`,
+/// A file matching none of them is always allowed
+fn allows_at(windows: &[Window], size: u64, now: NaiveTime) -> bool {
+    let min_size = windows
+        .iter()
+        .map(|window| window.min_size)
+        .filter(|min_size| *min_size <= size)
+        .max();
+
+    match min_size {
+        Some(min_size) => windows
+            .iter()
+            .filter(|window| window.min_size == min_size)
+            .any(|window| window.is_open(now)),
+        None => true,
+    }
+}
+
+/// Whether a file of this size may be synced now
+pub fn allows(size: u64) -> bool {
+    allows_at(&CONFIG.schedule, size, Local::now().time())
+}
+
+/// Whether syncing is allowed at all right now
+pub fn is_open() -> bool {
+    allows(0)
+}
+
+/// Uses the local size, a missing file only needs the schedule to be open
+pub fn allows_path(path: &Path) -> bool {
+    allows(path.metadata().map(|m| m.len()).unwrap_or(0))
+}
+
+/// Whether the operation may run now or should wait for its window
+pub fn allows_operation(op: &Operation) -> bool {
+    match op {
+        Operation::Upload(path) => allows_path(path),
+        Operation::Write(_, size) => allows(*size),
+        _ => is_open(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(from: &str, to: &str, min_size: u64) -> Window {
+        Window {
+            from: time(from),
+            to: time(to),
+            min_size,
+        }
+    }
+
+    fn time(value: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(value, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn overnight_windows_wrap_around_midnight() {
+        let night = window("22:00", "06:00", 0);
+
+        assert!(night.is_open(time("22:00")));
+        assert!(night.is_open(time("23:59")));
+        assert!(night.is_open(time("00:00")));
+        assert!(night.is_open(time("05:59")));
+        assert!(!night.is_open(time("06:00")));
+        assert!(!night.is_open(time("12:00")));
+        assert!(!night.is_open(time("21:59")));
+    }
+
+    #[test]
+    fn files_without_a_matching_window_are_allowed() {
+        let windows = [window("22:00", "06:00", 100)];
+
+        assert!(allows_at(&windows, 99, time("12:00")));
+        assert!(!allows_at(&windows, 100, time("12:00")));
+        assert!(allows_at(&windows, 100, time("23:00")));
+    }
+
+    #[test]
+    fn the_most_specific_window_applies() {
+        let windows = [window("08:00", "20:00", 0), window("22:00", "06:00", 1000)];
+
+        assert!(allows_at(&windows, 10, time("12:00")));
+        assert!(!allows_at(&windows, 10, time("23:00")));
+        assert!(!allows_at(&windows, 1000, time("12:00")));
+        assert!(allows_at(&windows, 1000, time("23:00")));
+    }
+
+    #[test]
+    fn windows_of_the_same_size_are_combined() {
+        let windows = [window("08:00", "09:00", 0), window("18:00", "19:00", 0)];
+
+        assert!(allows_at(&windows, 10, time("08:30")));
+        assert!(allows_at(&windows, 10, time("18:30")));
+        assert!(!allows_at(&windows, 10, time("12:00")));
+    }
+}
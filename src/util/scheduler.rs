@@ -0,0 +1,190 @@
+use anyhow::Result;
+use dashmap::DashMap;
+use futures::Future;
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::sync::{oneshot, Notify, Semaphore};
+
+/// Runs jobs on a bounded number of workers, jobs touching the same path run in order
+pub struct Scheduler {
+    workers: Arc<Semaphore>,
+    tails: Arc<DashMap<String, (u64, oneshot::Receiver<()>)>>,
+    next_id: AtomicU64,
+    active: Arc<AtomicUsize>,
+    idle: Arc<Notify>,
+}
+
+impl Scheduler {
+    pub fn new(workers: usize) -> Self {
+        Self {
+            workers: Arc::new(Semaphore::new(workers.max(1))),
+            tails: Arc::new(DashMap::new()),
+            next_id: AtomicU64::new(0),
+            active: Arc::new(AtomicUsize::new(0)),
+            idle: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn spawn<F>(&self, mut keys: Vec<String>, job: F)
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        keys.sort();
+        keys.dedup();
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut previous = vec![];
+        let mut done = vec![];
+
+        for key in &keys {
+            let (tx, rx) = oneshot::channel();
+            if let Some((_, (_, rx))) = self.tails.remove(key) {
+                previous.push(rx);
+            }
+            self.tails.insert(key.clone(), (id, rx));
+            done.push(tx);
+        }
+
+        let workers = self.workers.clone();
+        let tails = self.tails.clone();
+        let active = self.active.clone();
+        let idle = self.idle.clone();
+
+        active.fetch_add(1, Ordering::SeqCst);
+
+        tokio::spawn(async move {
+            // The sender is dropped once the previous job finishes, successfully or not
+            for rx in previous {
+                rx.await.ok();
+            }
+
+            let permit = workers.acquire_owned().await;
+
+            if let Err(err) = job.await {
+                log::error!("An error has occurred: {err:?}");
+            }
+
+            drop(permit);
+            drop(done);
+
+            for key in keys {
+                tails.remove_if(&key, |_, (tail_id, _)| *tail_id == id);
+            }
+
+            if active.fetch_sub(1, Ordering::SeqCst) == 1 {
+                idle.notify_waiters();
+            }
+        });
+    }
+
+    /// Number of jobs that are running or waiting to run
+    pub fn pending(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Waits until every spawned job has finished
+    pub async fn drain(&self) {
+        loop {
+            let idle = self.idle.notified();
+
+            if self.pending() == 0 {
+                return;
+            }
+
+            idle.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Mutex, time::Duration};
+    use tokio::time::sleep;
+
+    fn key(key: &str) -> Vec<String> {
+        vec![key.to_owned()]
+    }
+
+    #[tokio::test]
+    async fn jobs_of_the_same_path_run_in_order() {
+        let scheduler = Scheduler::new(4);
+        let order = Arc::new(Mutex::new(vec![]));
+
+        for (job, delay) in [(1, 50), (2, 0), (3, 20)] {
+            let order = order.clone();
+            scheduler.spawn(key("file"), async move {
+                sleep(Duration::from_millis(delay)).await;
+                order.lock().unwrap().push(job);
+                Ok(())
+            });
+        }
+
+        scheduler.drain().await;
+
+        assert_eq!(*order.lock().unwrap(), [1, 2, 3]);
+        assert_eq!(scheduler.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn failed_jobs_dont_block_the_next_ones() {
+        let scheduler = Scheduler::new(1);
+        let done = Arc::new(AtomicUsize::new(0));
+
+        scheduler.spawn(key("file"), async { anyhow::bail!("failed") });
+
+        let counter = done.clone();
+        scheduler.spawn(key("file"), async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+
+        scheduler.drain().await;
+
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn jobs_of_other_paths_run_concurrently() {
+        let scheduler = Scheduler::new(4);
+        let order = Arc::new(Mutex::new(vec![]));
+
+        for (job, delay) in [(1, 50), (2, 0)] {
+            let order = order.clone();
+            scheduler.spawn(key(&format!("file{job}")), async move {
+                sleep(Duration::from_millis(delay)).await;
+                order.lock().unwrap().push(job);
+                Ok(())
+            });
+        }
+
+        scheduler.drain().await;
+
+        assert_eq!(*order.lock().unwrap(), [2, 1]);
+    }
+
+    #[tokio::test]
+    async fn a_rename_waits_for_both_of_its_paths() {
+        let scheduler = Scheduler::new(4);
+        let order = Arc::new(Mutex::new(vec![]));
+
+        for (job, keys, delay) in [
+            (1, key("to"), 50),
+            (2, vec!["from".to_owned(), "to".to_owned()], 0),
+            (3, key("from"), 0),
+        ] {
+            let order = order.clone();
+            scheduler.spawn(keys, async move {
+                sleep(Duration::from_millis(delay)).await;
+                order.lock().unwrap().push(job);
+                Ok(())
+            });
+        }
+
+        scheduler.drain().await;
+
+        assert_eq!(*order.lock().unwrap(), [1, 2, 3]);
+    }
+}
@@ -0,0 +1,42 @@
+use tokio::sync::watch;
+
+lazy_static! {
+    static ref SHUTDOWN: (watch::Sender<bool>, watch::Receiver<bool>) = watch::channel(false);
+}
+
+pub fn request() {
+    SHUTDOWN.0.send_replace(true);
+}
+
+pub fn is_requested() -> bool {
+    *SHUTDOWN.1.borrow()
+}
+
+/// Resolves once a shutdown has been requested
+pub async fn requested() {
+    let mut rx = SHUTDOWN.1.clone();
+
+    while !*rx.borrow() {
+        if rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Resolves on SIGINT or SIGTERM
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("Couldn't listen for SIGTERM");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+}
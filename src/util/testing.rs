@@ -0,0 +1,200 @@
+use super::{key_to_path, set_settings_file_path};
+use crate::backends::*;
+use crate::SYNCED_PATHS;
+use std::{
+    env, fs,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, Once,
+    },
+};
+use tokio::sync::MutexGuard;
+
+static INIT: Once = Once::new();
+
+/// A scratch directory of this test run
+pub fn root() -> PathBuf {
+    env::temp_dir().join(format!("rsink-test-{}", std::process::id()))
+}
+
+/// Points the settings, cache and data files to the scratch directory,
+/// must be called before CONFIG is first used
+pub fn init() {
+    INIT.call_once(|| {
+        let root = root();
+        let settings = root.join("rsink.conf");
+
+        fs::create_dir_all(root.join("sync")).unwrap();
+        fs::write(
+            &settings,
+            format!(
+                r#"
+path = "{}"
+retry_delay = 10
+
+[backend]
+provider = "s3"
+bucket_name = "test"
+access_key_id = "test"
+secret_access_key = "test"
+"#,
+                root.join("sync").display()
+            ),
+        )
+        .unwrap();
+
+        env::set_var("XDG_CACHE_HOME", root.join("cache"));
+        env::set_var("XDG_DATA_HOME", root.join("data"));
+        set_settings_file_path(settings);
+    });
+}
+
+/// A fresh directory for the files of one test, outside of the synced one
+pub fn dir(name: &str) -> PathBuf {
+    init();
+
+    let dir = root().join(name);
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+lazy_static! {
+    static ref SYNC_DIR: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+/// Serializes the tests using the synced directory, which is emptied along with the synced paths
+pub async fn sync_dir() -> MutexGuard<'static, ()> {
+    init();
+
+    let guard = SYNC_DIR.lock().await;
+    let dir = root().join("sync");
+
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    SYNCED_PATHS.inner.clear();
+    guard
+}
+
+type Callback = Box<dyn Fn(&str) + Send + Sync>;
+
+/// A cloud for the tests, Recording the transfers it gets as `<op> <key>`
+#[derive(Default)]
+pub struct FakeCloud {
+    /// Keys listed by `list` and `sync`
+    pub objects: Vec<&'static str>,
+    /// Keys `sync` reports as deliberately deleted
+    pub tombstones: Vec<&'static str>,
+    /// Device holding the lease, if not this one
+    pub lease_holder: Option<&'static str>,
+    /// How many of the next transfers fail with a 503
+    pub failures: AtomicUsize,
+    /// Runs during each transfer, e.g. to queue a change meanwhile
+    pub during_transfer: Option<Callback>,
+    pub calls: Mutex<Vec<String>>,
+}
+
+impl FakeCloud {
+    fn transfer(&self, op: &str, key: &str) -> Result<()> {
+        self.calls.lock().unwrap().push(format!("{op} {key}"));
+
+        if let Some(during_transfer) = &self.during_transfer {
+            during_transfer(key);
+        }
+
+        let failing = self
+            .failures
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| x.checked_sub(1))
+            .is_ok();
+
+        if failing {
+            Err(HttpError(503).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Backend for FakeCloud {
+    async fn init(_options: BackendOptions) -> Self {
+        Self::default()
+    }
+
+    async fn list(&self, _prefix: &str) -> Result<Vec<RemoteObject>> {
+        Ok(self
+            .objects
+            .iter()
+            .map(|key| RemoteObject {
+                key: key.to_string(),
+                size: key.len() as u64,
+                last_modified: "2024-01-01T00:00:00Z".to_owned(),
+            })
+            .collect())
+    }
+
+    async fn restore(&self, path: &str) -> Result<()> {
+        self.transfer("restore", path)
+    }
+
+    async fn remove(&self, path: &str) -> Result<()> {
+        self.transfer("remove", path)
+    }
+
+    async fn download(&self, path: &str) -> Result<Vec<u8>> {
+        self.transfer("download", path)?;
+        Ok(path.as_bytes().to_vec())
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool> {
+        Ok(self.objects.contains(&path))
+    }
+
+    async fn rename(&self, old_path: &str, path: &str) -> Result<()> {
+        self.transfer("rename", &format!("{old_path} {path}"))
+    }
+
+    async fn sync(&self, _dry_run: bool) -> Result<Vec<Operation>> {
+        Ok(self
+            .objects
+            .iter()
+            .map(|key| Operation::Checked(key_to_path(key)))
+            .chain(
+                self.tombstones
+                    .iter()
+                    .map(|key| Operation::Deleted(key_to_path(key))),
+            )
+            .collect())
+    }
+
+    async fn upload(&self, path: &str, _content: &[u8]) -> Result<()> {
+        self.transfer("upload", path)
+    }
+
+    async fn acquire_lease(&self, _device: &str, _ttl: Duration) -> Result<Option<Lease>> {
+        Ok(self.lease_holder.map(|device| Lease {
+            device: device.to_owned(),
+            expires_at: i64::MAX,
+        }))
+    }
+
+    async fn release_lease(&self, _device: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>> {
+        Ok(vec![])
+    }
+
+    async fn register_device(&self, _device: Device) -> Result<()> {
+        Ok(())
+    }
+
+    async fn last_writer(&self, _path: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
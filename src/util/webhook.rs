@@ -0,0 +1,177 @@
+use super::config::{Webhook, WebhookEvent, CONFIG};
+use crate::backends::{check_status, retry};
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::{sync::Mutex, time::Duration};
+use tokio::{task::JoinHandle, time::timeout};
+
+lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::new();
+    static ref DELIVERIES: Mutex<Vec<JoinHandle<()>>> = Mutex::new(vec![]);
+}
+
+/// How long to wait for the deliveries still running on exit
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn is_wanted(event: WebhookEvent) -> bool {
+    CONFIG
+        .webhook
+        .as_ref()
+        .is_some_and(|webhook| webhook.events.contains(&event))
+}
+
+/// Hex HMAC-SHA256 of the body, sent as `X-RSink-Signature: sha256=<signature>`
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("Any key length works");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+async fn deliver(webhook: &Webhook, event: WebhookEvent, body: Vec<u8>) -> Result<()> {
+    let mut request = CLIENT
+        .post(&webhook.url)
+        .timeout(Duration::from_secs(webhook.timeout))
+        .header("Content-Type", "application/json")
+        .header("X-RSink-Event", format!("{event:?}").to_lowercase())
+        .body(body.clone());
+
+    if let Some(secret) = &webhook.secret {
+        request = request.header(
+            "X-RSink-Signature",
+            format!("sha256={}", sign(secret, &body)),
+        );
+    }
+
+    check_status(request.send().await?.status().as_u16())
+}
+
+/// POSTs the event to the webhook in the background, retrying transient failures
+pub fn send(event: WebhookEvent, mut payload: Value) {
+    if !is_wanted(event) {
+        return;
+    }
+
+    payload["event"] = json!(event);
+
+    let webhook = CONFIG.webhook.as_ref().unwrap();
+    let body = serde_json::to_vec(&payload).unwrap();
+    let handle = tokio::spawn(async move {
+        if let Err(err) = retry(|| deliver(webhook, event, body.clone())).await {
+            log::error!("Couldn't deliver the {event:?} webhook: {err:#}");
+        }
+    });
+
+    let mut deliveries = DELIVERIES.lock().unwrap();
+    deliveries.retain(|x| !x.is_finished());
+    deliveries.push(handle);
+}
+
+/// Waits a bit for the running deliveries, so they aren't lost on exit
+pub async fn flush() {
+    let deliveries = std::mem::take(&mut *DELIVERIES.lock().unwrap());
+
+    if timeout(FLUSH_TIMEOUT, futures::future::join_all(deliveries))
+        .await
+        .is_err()
+    {
+        log::warn!("Some webhook deliveries didn't finish in time");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    // RFC 4231, test case 2
+    const KEY: &str = "Jefe";
+    const DATA: &[u8] = b"what do ya want for nothing?";
+    const SIGNATURE: &str = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        assert_eq!(sign(KEY, DATA), SIGNATURE);
+    }
+
+    /// Reads a request, returns its lowercased head and its body
+    async fn read_request(stream: &mut TcpStream) -> (String, Vec<u8>) {
+        let mut buffer = vec![];
+        let mut chunk = [0; 1024];
+
+        let end = loop {
+            let read = stream.read(&mut chunk).await.unwrap();
+            assert!(read > 0, "The connection was closed mid-request");
+            buffer.extend_from_slice(&chunk[..read]);
+
+            if let Some(end) = buffer.windows(4).position(|x| x == b"\r\n\r\n") {
+                break end + 4;
+            }
+        };
+
+        let head = String::from_utf8_lossy(&buffer[..end]).to_lowercase();
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .map_or(0, |x| x.trim().parse().unwrap());
+
+        while buffer.len() < end + length {
+            let read = stream.read(&mut chunk).await.unwrap();
+            buffer.extend_from_slice(&chunk[..read]);
+        }
+
+        (head, buffer[end..].to_vec())
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_signed_and_retried() {
+        testing::init();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let webhook = Webhook {
+            url: format!("http://{}/hook", listener.local_addr().unwrap()),
+            secret: Some(KEY.to_owned()),
+            events: vec![WebhookEvent::Pass],
+            timeout: 5,
+        };
+        let server = tokio::spawn(async move {
+            let mut requests = vec![];
+
+            for status in ["500 Internal Server Error", "200 OK"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                requests.push(read_request(&mut stream).await);
+                stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        )
+                        .as_bytes(),
+                    )
+                    .await
+                    .unwrap();
+            }
+
+            requests
+        });
+
+        retry(|| deliver(&webhook, WebhookEvent::Pass, DATA.to_vec()))
+            .await
+            .unwrap();
+
+        let requests = server.await.unwrap();
+
+        assert_eq!(requests.len(), 2);
+
+        for (head, body) in requests {
+            assert!(head.starts_with("post /hook "));
+            assert!(head.contains("x-rsink-event: pass\r\n"));
+            assert!(head.contains(&format!("x-rsink-signature: sha256={SIGNATURE}\r\n")));
+            assert_eq!(body, DATA);
+        }
+    }
+}